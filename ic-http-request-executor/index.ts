@@ -1,97 +1,317 @@
+import { createHash } from "node:crypto";
 import IcWebSocket, { createWsConfig, generateRandomIdentity } from "ic-websocket-js";
+// installed along with ic-websocket-js
+import { Secp256k1KeyIdentity } from "@dfinity/identity-secp256k1";
 import { ic_side_services_backend, canisterId } from "./src/canister/declarations/ic_side_services_backend";
+import packageJson from "./package.json";
+import type {
+  ChunkedBody,
+  HttpOverWsMessage,
+  HttpRequest,
+  HttpRequestId,
+} from "./src/canister/declarations/ic_side_services_backend/ic_side_services_backend.did";
 
 /**
  * How long to wait before trying to reconnect
  * in case the WS connection with the canister was manually closed
  */
 const RECONNECT_AFTER_MS = 45_000;
+/**
+ * Response bodies larger than this are sent in chunks.
+ * Each chunk is sent to the canister in its own update call,
+ * so it only has to stay below the ingress message limit
+ */
+const MAX_CHUNK_BYTES = 512 * 1024;
+/**
+ * The version of the http-over-ws protocol spoken by this executor
+ */
+const PROTOCOL_VERSION = 4;
 
 const icNetworkUrl = process.env.IC_NETWORK_URL as string;
 const gatewayUrl = process.env.IC_WS_GATEWAY_URL as string;
+/**
+ * The canister only sends requests to the executors approved by its controllers,
+ * so the identity must stay the same across restarts.
+ */
+const identitySeedPhrase = process.env.EXECUTOR_IDENTITY_SEED_PHRASE;
+
+if (!identitySeedPhrase) {
+  console.warn(
+    "EXECUTOR_IDENTITY_SEED_PHRASE not set, using a random identity.",
+    "The executor won't receive requests until the canister controllers approve it."
+  );
+}
 
 const wsConfig = createWsConfig({
   canisterId,
   canisterActor: ic_side_services_backend,
   networkUrl: icNetworkUrl,
-  identity: generateRandomIdentity(),
+  identity: identitySeedPhrase
+    ? Secp256k1KeyIdentity.fromSeedPhrase(identitySeedPhrase)
+    : generateRandomIdentity(),
 });
 
 console.log("Canister ID:", canisterId);
 
+const sha256 = (data: Uint8Array): Uint8Array => {
+  return new Uint8Array(createHash("sha256").update(data).digest());
+};
+
+/**
+ * A request whose body chunks are being received.
+ */
+type IncomingChunkedRequest = {
+  request: HttpRequest,
+  body: ChunkedBody,
+  chunks: Map<number, Uint8Array>,
+};
+
+const assembleChunkedBody = (body: ChunkedBody, chunks: Map<number, Uint8Array>): Uint8Array => {
+  const assembled = new Uint8Array(Number(body.total_length));
+  let offset = 0;
+  for (let i = 0; i < body.chunks_count; i++) {
+    const chunk = chunks.get(i)!;
+    if (offset + chunk.length > assembled.length) {
+      throw new Error("chunks exceed the declared body length");
+    }
+    assembled.set(chunk, offset);
+    offset += chunk.length;
+  }
+
+  if (offset !== assembled.length) {
+    throw new Error(`body is ${offset} bytes, expected ${assembled.length}`);
+  }
+  if (Buffer.compare(sha256(assembled), new Uint8Array(body.sha256)) !== 0) {
+    throw new Error("body hash mismatch");
+  }
+
+  return assembled;
+};
+
+/**
+ * Thrown when the host of the request can't be reached
+ */
+class UpstreamError extends Error {}
+
 const openWsConnection = () => {
   const ws = new IcWebSocket(gatewayUrl, {}, wsConfig);
   const principal = ws.getPrincipal().toString();
   console.log("WebSocket principal:", principal);
 
-  ws.onopen = () => {
-    console.log("WebSocket connected with principal", principal);
-  };
+  const incomingChunkedRequests = new Map<HttpRequestId, IncomingChunkedRequest>();
+  /**
+   * Used to abort the requests cancelled by the canister
+   */
+  const inFlightRequests = new Map<HttpRequestId, AbortController>();
+  /**
+   * Set when the canister sends its Hello
+   */
+  let canisterProtocolVersion = 1;
 
-  ws.onmessage = async (ev) => {
-    const incomingMessage = ev.data;
-    // console.log("Message", incomingMessage);
+  const sendMessage = (message: HttpOverWsMessage) => {
+    ws.send(message);
+  };
 
-    if ("HttpRequest" in incomingMessage) {
-      const requestId = incomingMessage.HttpRequest[0];
-      const request = incomingMessage.HttpRequest[1];
+  const executeHttpRequest = async (requestId: HttpRequestId, request: HttpRequest, requestBody: Uint8Array | null) => {
+    const url = new URL(request.url);
+    const method = Object.keys(request.method)[0]; // workaround to get the candid enum
+    const headers = new Headers(
+      request.headers.map(({ name, value }) => [name, value] as [string, string])
+    );
+    // only accept the encodings advertised to the canister, fetch would also accept br
+    if (!headers.has("accept-encoding")) {
+      headers.set("accept-encoding", "gzip, deflate");
+    }
+    const body = (requestBody && method !== "GET")
+      ? requestBody
+      : null;
 
-      const url = new URL(request.url);
-      const method = Object.keys(request.method)[0]; // workaround to get the candid enum
-      const headers = new Headers(
-        request.headers.map(({ name, value }) => [name, value] as [string, string])
-      );
-      const body = (request.body.length > 0 && method !== "GET")
-        ? new Uint8Array(request.body[0]!)
-        : null;
+    // headers, bodies and query strings may carry credentials, don't log them
+    const loggedUrl = url.origin + url.pathname;
+    console.log(
+      "\nExecuting HTTP request:",
+      "\nid:", requestId,
+      "\nurl:", loggedUrl,
+      "\nmethod:", method,
+      "\nbody bytes:", body?.length ?? 0,
+    );
 
-      console.log(
-        "\nExecuting HTTP request:",
-        "\nurl:", url.toString(),
-        "\nmethod:", method,
-        "\nheaders:", headers,
-        "\nbody bytes:", body?.length,
-        "\nbody:", body ? new TextDecoder().decode(body) : null
-      );
+    const abortController = new AbortController();
+    inFlightRequests.set(requestId, abortController);
 
+    try {
+      let response: Response;
+      let responseBody: Uint8Array;
       try {
-        const response = await fetch(url, {
+        response = await fetch(url, {
           method,
           headers,
           body,
+          signal: abortController.signal,
         });
 
-        const responseBody = new Uint8Array(await response.arrayBuffer());
+        responseBody = new Uint8Array(await response.arrayBuffer());
+      } catch (e) {
+        throw new UpstreamError(String(e));
+      }
 
-        console.log(
-          "HTTP response:",
-          "\nurl:", request.url,
-          "\nstatus:", response.status,
-          "\nbody bytes:", responseBody.byteLength,
-          "\nbody:", new TextDecoder().decode(responseBody),
-        );
+      console.log(
+        "HTTP response:",
+        "\nid:", requestId,
+        "\nurl:", loggedUrl,
+        "\nstatus:", response.status,
+        "\nbody bytes:", responseBody.byteLength,
+      );
+
+      const status = BigInt(response.status);
+      // fetch decompresses the body, so these headers don't describe it anymore
+      const responseHeaders = Array.from(response.headers.entries())
+        .filter(([key]) => key !== "content-encoding" && key !== "content-length")
+        .map(([key, value]) => ({
+          name: key,
+          value,
+        }));
 
-        ws.send({
+      if (responseBody.byteLength <= MAX_CHUNK_BYTES) {
+        sendMessage({
           HttpResponse: [
             requestId,
             {
-              status: BigInt(response.status),
-              headers: Array.from(response.headers.entries()).map(([key, value]) => ({
-                name: key,
-                value,
-              })),
+              status,
+              headers: responseHeaders,
               body: responseBody,
             },
           ],
         });
+      } else {
+        const chunksCount = Math.ceil(responseBody.byteLength / MAX_CHUNK_BYTES);
 
-        console.log("Sent response over WebSocket.");
-      } catch (e) {
-        console.error("http-over-ws: error", e);
-        ws.send({
+        sendMessage({
+          ChunkedHttpResponse: [
+            requestId,
+            {
+              status,
+              headers: responseHeaders,
+              body: new Uint8Array(),
+            },
+            {
+              total_length: BigInt(responseBody.byteLength),
+              chunks_count: chunksCount,
+              sha256: sha256(responseBody),
+            },
+          ],
+        });
+
+        for (let index = 0; index < chunksCount; index++) {
+          sendMessage({
+            BodyChunk: [
+              requestId,
+              {
+                index,
+                data: responseBody.subarray(index * MAX_CHUNK_BYTES, (index + 1) * MAX_CHUNK_BYTES),
+              },
+            ],
+          });
+        }
+      }
+
+      console.log("Sent response over WebSocket.");
+    } catch (e) {
+      if (abortController.signal.aborted) {
+        // the canister ignores the responses of cancelled requests
+        console.log("HTTP request", requestId, "cancelled.");
+        return;
+      }
+
+      console.error("http-over-ws: error", e);
+      // the canister counts the upstream errors against the host of the request
+      if (e instanceof UpstreamError && canisterProtocolVersion >= 4) {
+        sendMessage({
+          UpstreamError: [requestId, e.message],
+        });
+      } else {
+        sendMessage({
           Error: [[requestId], String(e)],
         });
       }
+    } finally {
+      inFlightRequests.delete(requestId);
+    }
+  };
+
+  ws.onopen = () => {
+    console.log("WebSocket connected with principal", principal);
+
+    // the canister replies with its Hello
+    sendMessage({
+      Capabilities: {
+        protocol_version: PROTOCOL_VERSION,
+        supported_methods: [{ GET: null }, { POST: null }, { PUT: null }, { HEAD: null }, { DELETE: null }],
+        max_body_bytes: [],
+        supported_encodings: ["gzip", "deflate"],
+        executor_version: packageJson.version,
+      },
+    });
+  };
+
+  ws.onmessage = async (ev) => {
+    const incomingMessage: HttpOverWsMessage = ev.data;
+    // console.log("Message", incomingMessage);
+
+    if ("Hello" in incomingMessage) {
+      const { protocol_version, min_protocol_version } = incomingMessage.Hello;
+      console.log("Canister speaks http-over-ws protocol version", protocol_version);
+      canisterProtocolVersion = protocol_version;
+
+      if (PROTOCOL_VERSION < min_protocol_version) {
+        console.error(
+          "http-over-ws: protocol version", PROTOCOL_VERSION,
+          "not supported by the canister, which requires at least", min_protocol_version
+        );
+      }
+    } else if ("HttpRequest" in incomingMessage) {
+      const [requestId, request] = incomingMessage.HttpRequest;
+      const body = request.body.length > 0
+        ? new Uint8Array(request.body[0]!)
+        : null;
+
+      await executeHttpRequest(requestId, request, body);
+    } else if ("ChunkedHttpRequest" in incomingMessage) {
+      const [requestId, request, body] = incomingMessage.ChunkedHttpRequest;
+
+      incomingChunkedRequests.set(requestId, { request, body, chunks: new Map() });
+    } else if ("BodyChunk" in incomingMessage) {
+      const [requestId, chunk] = incomingMessage.BodyChunk;
+      const incoming = incomingChunkedRequests.get(requestId);
+      if (!incoming) {
+        console.warn("http-over-ws: chunk received for unknown request", requestId);
+        return;
+      }
+
+      incoming.chunks.set(chunk.index, new Uint8Array(chunk.data));
+      if (incoming.chunks.size < incoming.body.chunks_count) {
+        return;
+      }
+
+      incomingChunkedRequests.delete(requestId);
+
+      let body: Uint8Array;
+      try {
+        body = assembleChunkedBody(incoming.body, incoming.chunks);
+      } catch (e) {
+        console.error("http-over-ws: invalid chunked request body", e);
+        sendMessage({
+          Error: [[requestId], `Invalid chunked request body: ${String(e)}`],
+        });
+        return;
+      }
+
+      await executeHttpRequest(requestId, incoming.request, body);
+    } else if ("Cancel" in incomingMessage) {
+      const requestId = incomingMessage.Cancel;
+
+      incomingChunkedRequests.delete(requestId);
+      inFlightRequests.get(requestId)?.abort();
     } else if ("Error" in incomingMessage) {
       console.error("http-over-ws: incoming error:", incomingMessage.Error);
     }
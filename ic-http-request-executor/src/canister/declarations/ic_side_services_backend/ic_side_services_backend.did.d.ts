@@ -2,6 +2,7 @@ import type { Principal } from '@dfinity/principal';
 import type { ActorMethod } from '@dfinity/agent';
 
 export type BitcoinAddress = string;
+export interface BodyChunk { 'data' : Uint8Array | number[], 'index' : number }
 export interface CanisterOutputCertifiedMessages {
   'messages' : Array<CanisterOutputMessage>,
   'cert' : Uint8Array | number[],
@@ -30,6 +31,15 @@ export interface CanisterWsOpenArguments {
 }
 export type CanisterWsOpenResult = { 'Ok' : null } |
   { 'Err' : string };
+export interface CanisterHello {
+  'protocol_version' : number,
+  'min_protocol_version' : number,
+}
+export interface ChunkedBody {
+  'sha256' : Uint8Array | number[],
+  'total_length' : bigint,
+  'chunks_count' : number,
+}
 export interface ClientKey {
   'client_principal' : ClientPrincipal,
   'client_nonce' : bigint,
@@ -39,6 +49,13 @@ export interface ConnectedClients {
   'busy_clients' : Array<[Principal, Uint32Array | number[]]>,
   'idle_clients' : Array<Principal>,
 }
+export interface ExecutorCapabilities {
+  'executor_version' : string,
+  'supported_encodings' : Array<string>,
+  'protocol_version' : number,
+  'max_body_bytes' : [] | [bigint],
+  'supported_methods' : Array<HttpMethod>,
+}
 export type FluxNetwork = { 'mainnet' : null } |
   { 'local' : null } |
   { 'testnet' : null };
@@ -51,8 +68,15 @@ export type HttpMethod = { 'GET' : null } |
   { 'DELETE' : null } |
   { 'HEAD' : null } |
   { 'POST' : null };
-export type HttpOverWsMessage = { 'Error' : [[] | [HttpRequestId], string] } |
+export type HttpOverWsMessage = { 'Hello' : CanisterHello } |
+  { 'Capabilities' : ExecutorCapabilities } |
+  { 'Cancel' : HttpRequestId } |
+  { 'UpstreamError' : [HttpRequestId, string] } |
+  { 'Error' : [[] | [HttpRequestId], string] } |
   { 'HttpRequest' : [HttpRequestId, HttpRequest] } |
+  { 'ChunkedHttpRequest' : [HttpRequestId, HttpRequest, ChunkedBody] } |
+  { 'BodyChunk' : [HttpRequestId, BodyChunk] } |
+  { 'ChunkedHttpResponse' : [HttpRequestId, HttpResponse, ChunkedBody] } |
   { 'HttpResponse' : [HttpRequestId, HttpResponse] };
 export interface HttpRequest {
   'url' : string,
@@ -83,9 +83,40 @@ export const idlFactory = ({ IDL }) => {
     'body' : IDL.Vec(IDL.Nat8),
     'headers' : IDL.Vec(HttpHeader),
   });
+  const ChunkedBody = IDL.Record({
+    'sha256' : IDL.Vec(IDL.Nat8),
+    'total_length' : IDL.Nat64,
+    'chunks_count' : IDL.Nat32,
+  });
+  const BodyChunk = IDL.Record({
+    'data' : IDL.Vec(IDL.Nat8),
+    'index' : IDL.Nat32,
+  });
+  const CanisterHello = IDL.Record({
+    'protocol_version' : IDL.Nat32,
+    'min_protocol_version' : IDL.Nat32,
+  });
+  const ExecutorCapabilities = IDL.Record({
+    'executor_version' : IDL.Text,
+    'supported_encodings' : IDL.Vec(IDL.Text),
+    'protocol_version' : IDL.Nat32,
+    'max_body_bytes' : IDL.Opt(IDL.Nat64),
+    'supported_methods' : IDL.Vec(HttpMethod),
+  });
   const HttpOverWsMessage = IDL.Variant({
+    'Hello' : CanisterHello,
+    'Capabilities' : ExecutorCapabilities,
+    'Cancel' : HttpRequestId,
+    'UpstreamError' : IDL.Tuple(HttpRequestId, IDL.Text),
     'Error' : IDL.Tuple(IDL.Opt(HttpRequestId), IDL.Text),
     'HttpRequest' : IDL.Tuple(HttpRequestId, HttpRequest),
+    'ChunkedHttpRequest' : IDL.Tuple(HttpRequestId, HttpRequest, ChunkedBody),
+    'BodyChunk' : IDL.Tuple(HttpRequestId, BodyChunk),
+    'ChunkedHttpResponse' : IDL.Tuple(
+      HttpRequestId,
+      HttpResponse,
+      ChunkedBody,
+    ),
     'HttpResponse' : IDL.Tuple(HttpRequestId, HttpResponse),
   });
   const CanisterWsMessageResult = IDL.Variant({
@@ -5,111 +5,199 @@ use flux_types::models::*;
 use crate::{
     flux,
     flux_api::{
-        CONTENT_TYPE_TEXT_PLAIN_HEADER, DEFAULT_HTTP_REQUEST_TIMEOUT_MS, FLUX_API_BASE_URL,
-        FLUX_STATE,
+        flux_response_transform_context, CONTENT_TYPE_TEXT_PLAIN_HEADER,
+        DEFAULT_HTTP_REQUEST_RETRY_POLICY, DEFAULT_HTTP_REQUEST_TIMEOUT_MS, FLUX_API_BASE_URL,
+        FLUX_STATE, ZELIDAUTH_HEADER_NAME,
+    },
+    http_over_ws::{
+        decode_json_response, execute_http_request, execute_http_request_async,
+        register_http_callback, wait_for_http_request, HttpCallbackArgs, HttpCallbackContext,
+        HttpHeader, HttpMethod, HttpRequestId, HttpRequestOptions, SensitiveData,
     },
-    http_over_ws::{execute_http_request, HttpHeader, HttpMethod, HttpRequestId, HttpResponse},
     logger::log,
     sign_with_ecdsa, NETWORK,
 };
 
+const LOGOUT_CALLBACK_NAME: &str = "flux_logout";
+
+pub(super) fn register_http_callbacks() {
+    register_http_callback(LOGOUT_CALLBACK_NAME, |args| Box::pin(logout_cb(args)));
+}
+
+/// Returns the id of the loginphrase request.
+/// The zelidauth header is set once the signed loginphrase is verified.
 pub fn login() -> HttpRequestId {
     let loginphrase_url = FLUX_API_BASE_URL.join("/id/loginphrase").unwrap();
+    let zelid = flux::get_p2pkh_address(NETWORK.with(|n| n.get()), flux::P2PKHAddress::ZelId);
 
-    async fn verifylogin_cb(res: HttpResponse) {
-        if res.status != 200 {
-            log(&format!("verifylogin failed with status: {}", res.status));
-            return;
-        }
+    let loginphrase_request_id = execute_http_request(
+        loginphrase_url,
+        HttpMethod::GET,
+        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
+        None,
+        HttpRequestOptions {
+            timeout_ms: Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            retry_policy: Some(DEFAULT_HTTP_REQUEST_RETRY_POLICY),
+            transform: Some(flux_response_transform_context()),
+            sensitive: Some(SensitiveData::from_body_fields(&["data"])),
+            ..Default::default()
+        },
+    );
+
+    ic_cdk::spawn(complete_login(loginphrase_request_id, zelid));
+
+    loginphrase_request_id
+}
 
-        let VerifyLogin200Response { data, status } = serde_json::from_slice(&res.body).unwrap();
-        if let verify_login_200_response::Status::Error = status.unwrap() {
-            log(&format!("verifylogin error: {:?}", data));
+async fn complete_login(loginphrase_request_id: HttpRequestId, zelid: String) {
+    let res = match wait_for_http_request(loginphrase_request_id).await {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!("loginphrase failed: {:?}", err));
             return;
         }
+    };
 
-        FLUX_STATE.with(|h| {
-            h.borrow_mut()
-                .set_auth_header_from_verifylogin_response_data(*data.unwrap());
-        });
+    if res.status != 200 {
+        log(&format!("loginphrase failed with status: {}", res.status));
+        return;
     }
 
-    async fn loginphrase_cb(res: HttpResponse) {
-        if res.status != 200 {
-            log(&format!("loginphrase failed with status: {}", res.status));
+    let LoginPhrase200Response { data, status } = match decode_json_response(res) {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "loginphrase response could not be decoded: {}",
+                err
+            ));
             return;
         }
+    };
+    if status != Some(Status::Success) {
+        log(&format!("loginphrase error: {:?}", data));
+        return;
+    }
 
-        let LoginPhrase200Response { data, status } = serde_json::from_slice(&res.body).unwrap();
-        if let Status::Error = status.unwrap() {
-            log(&format!("loginphrase error: {:?}", data));
-            return;
-        }
-
-        let login_phrase = data.unwrap();
-
-        log(&format!("loginphrase: {}", login_phrase));
+    let Some(login_phrase) = data else {
+        log("loginphrase response has no data");
+        return;
+    };
 
-        // get the signature for the loginphrase
-        let signature = sign_with_ecdsa(login_phrase.clone(), None).await;
+    log("loginphrase received");
 
-        let body = ZelIdLogin {
-            login_phrase: Some(login_phrase),
-            zelid: Some(flux::get_p2pkh_address(
-                NETWORK.with(|n| n.get()),
-                flux::P2PKHAddress::ZelId,
-            )),
-            signature: Some(signature),
-        };
+    // get the signature for the loginphrase
+    let signature = sign_with_ecdsa(login_phrase.clone(), None).await;
 
-        let verifylogin_url = FLUX_API_BASE_URL.join("/id/verifylogin").unwrap();
+    let body = ZelIdLogin {
+        login_phrase: Some(login_phrase),
+        zelid: Some(zelid.clone()),
+        signature: Some(signature),
+    };
 
-        execute_http_request(
-            verifylogin_url,
-            HttpMethod::POST,
-            vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
-            Some(serde_json::to_string(&body).unwrap()),
-            Some(|res| Box::pin(verifylogin_cb(res))),
-            Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
-        );
-    }
+    let verifylogin_url = FLUX_API_BASE_URL.join("/id/verifylogin").unwrap();
 
-    execute_http_request(
-        loginphrase_url,
-        HttpMethod::GET,
+    let res = match execute_http_request_async(
+        verifylogin_url,
+        HttpMethod::POST,
         vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
-        None,
-        Some(|res| Box::pin(loginphrase_cb(res))),
-        // this request can take longer to complete due to the sign_with_ecdsa in the callback
-        Some(2 * DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+        Some(serde_json::to_string(&body).unwrap()),
+        HttpRequestOptions {
+            timeout_ms: Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            retry_policy: Some(DEFAULT_HTTP_REQUEST_RETRY_POLICY),
+            transform: Some(flux_response_transform_context()),
+            sensitive: Some(SensitiveData::from_body_fields(&[
+                "loginPhrase",
+                "signature",
+            ])),
+            ..Default::default()
+        },
     )
-}
+    .await
+    {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!("verifylogin failed: {:?}", err));
+            return;
+        }
+    };
 
-pub fn logout() -> HttpRequestId {
-    let zelidauth = get_zelidauth_or_trap();
-    let logout_url = FLUX_API_BASE_URL.join("/id/logoutcurrentsession").unwrap();
+    if res.status != 200 {
+        log(&format!("verifylogin failed with status: {}", res.status));
+        return;
+    }
 
-    async fn logout_cb(res: HttpResponse) {
-        if res.status != 200 {
-            log(&format!("logout failed with status: {}", res.status));
+    let VerifyLogin200Response { data, status } = match decode_json_response(res) {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "verifylogin response could not be decoded: {}",
+                err
+            ));
             return;
         }
+    };
+    if status != Some(verify_login_200_response::Status::Success) {
+        log(&format!("verifylogin error: {:?}", data));
+        return;
+    }
 
-        log("logout successful");
-
-        FLUX_STATE.with(|b| b.borrow_mut().reset_auth_header());
+    let Some(data) = data else {
+        log("verifylogin response has no data");
+        return;
+    };
+    if data.zelid.as_ref() != Some(&zelid) {
+        log(&format!(
+            "verifylogin returned zelid {:?}, expected {}",
+            data.zelid, zelid
+        ));
+        return;
     }
 
+    FLUX_STATE.with(|h| {
+        h.borrow_mut()
+            .set_auth_header_from_verifylogin_response_data(*data);
+    });
+}
+
+pub fn logout() -> HttpRequestId {
+    let zelidauth = get_zelidauth_or_trap();
+    let logout_url = FLUX_API_BASE_URL.join("/id/logoutcurrentsession").unwrap();
+
     execute_http_request(
         logout_url,
         HttpMethod::GET,
         vec![zelidauth],
         None,
-        Some(|res| Box::pin(logout_cb(res))),
-        Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+        HttpRequestOptions {
+            callback: Some(HttpCallbackContext::from_name(LOGOUT_CALLBACK_NAME, vec![])),
+            timeout_ms: Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            retry_policy: Some(DEFAULT_HTTP_REQUEST_RETRY_POLICY),
+            transform: Some(flux_response_transform_context()),
+            sensitive: Some(SensitiveData::from_headers(&[ZELIDAUTH_HEADER_NAME])),
+            ..Default::default()
+        },
     )
 }
 
+async fn logout_cb(args: HttpCallbackArgs) {
+    let res = match args.result {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!("logout failed: {:?}", err));
+            return;
+        }
+    };
+
+    if res.status != 200 {
+        log(&format!("logout failed with status: {}", res.status));
+        return;
+    }
+
+    log("logout successful");
+
+    FLUX_STATE.with(|b| b.borrow_mut().reset_auth_header());
+}
+
 pub fn get_zelidauth() -> Option<HttpHeader> {
     FLUX_STATE.with(|b| b.borrow().get_zelid_auth_header())
 }
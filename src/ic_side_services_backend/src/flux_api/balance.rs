@@ -1,42 +1,99 @@
 use crate::{
     flux,
-    flux_api::{DEFAULT_HTTP_REQUEST_TIMEOUT_MS, FLUX_API_BASE_URL, FLUX_STATE},
-    http_over_ws::{execute_http_request, HttpMethod, HttpRequestId, HttpResponse},
+    flux_api::{
+        flux_response_transform_context, DEFAULT_HTTPS_OUTCALL_POLICY,
+        DEFAULT_HTTP_REQUEST_TIMEOUT_MS, FLUX_API_BASE_URL, FLUX_STATE,
+    },
+    http_over_ws::{
+        decode_json_response, execute_http_request, register_http_callback, HttpCallbackArgs,
+        HttpCallbackContext, HttpMethod, HttpRequestId, HttpRequestOptions, HttpRequestQuorum,
+    },
     logger::log,
     NETWORK,
 };
 
-pub fn fetch_balance() -> HttpRequestId {
-    let mut balance_url = FLUX_API_BASE_URL.join("/explorer/balance").unwrap();
-    balance_url.query_pairs_mut().append_pair(
-        "address",
-        &flux::get_p2pkh_address(NETWORK.with(|n| n.get()), flux::P2PKHAddress::ZCash),
-    );
-
-    async fn balance_cb(res: HttpResponse) {
-        if res.status != 200 {
-            log(&format!("balance failed with status: {}", res.status));
-            return;
-        }
+const BALANCE_CALLBACK_NAME: &str = "flux_balance";
 
-        let res_body = serde_json::from_slice(&res.body).unwrap();
+pub(super) fn register_http_callbacks() {
+    register_http_callback(BALANCE_CALLBACK_NAME, |args| Box::pin(balance_cb(args)));
+}
 
-        FLUX_STATE.with(|b| {
-            b.borrow_mut()
-                .set_balance_from_getaddressbalance_response(&res_body)
-        });
-    }
+pub fn fetch_balance() -> HttpRequestId {
+    let address = flux::get_p2pkh_address(NETWORK.with(|n| n.get()), flux::P2PKHAddress::ZCash);
+
+    let mut balance_url = FLUX_API_BASE_URL.join("/explorer/balance").unwrap();
+    balance_url
+        .query_pairs_mut()
+        .append_pair("address", &address);
 
     execute_http_request(
         balance_url,
         HttpMethod::GET,
         vec![],
         None,
-        Some(|res| Box::pin(balance_cb(res))),
-        Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+        HttpRequestOptions {
+            callback: Some(HttpCallbackContext::with_candid_context(
+                BALANCE_CALLBACK_NAME,
+                &address,
+            )),
+            timeout_ms: Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            https_outcall: Some(DEFAULT_HTTPS_OUTCALL_POLICY),
+            // the balance is used to pay for deployments, don't trust a single client
+            quorum: Some(HttpRequestQuorum {
+                clients: 3,
+                min_agreeing: 2,
+                // already dropped by the flux response transform
+                ignored_headers: vec![],
+                body_normalizer: None,
+            }),
+            transform: Some(flux_response_transform_context()),
+            ..Default::default()
+        },
     )
 }
 
+async fn balance_cb(args: HttpCallbackArgs) {
+    let address: String = match args.decode_context() {
+        Ok(context) => context,
+        Err(err) => {
+            log(&format!("balance callback has an invalid context: {}", err));
+            return;
+        }
+    };
+
+    let res = match args.result {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!("balance of {} failed: {:?}", address, err));
+            return;
+        }
+    };
+
+    if res.status != 200 {
+        log(&format!(
+            "balance of {} failed with status: {}",
+            address, res.status
+        ));
+        return;
+    }
+
+    let res_body = match decode_json_response(res) {
+        Ok(res_body) => res_body,
+        Err(err) => {
+            log(&format!(
+                "balance of {} response could not be decoded: {}",
+                address, err
+            ));
+            return;
+        }
+    };
+
+    FLUX_STATE.with(|b| {
+        b.borrow_mut()
+            .set_balance_from_getaddressbalance_response(&res_body)
+    });
+}
+
 /// Returns FLUX token balance.
 pub fn get_balance() -> Option<f32> {
     FLUX_STATE.with(|b| b.borrow().get_balance().map(|v| (v as f32) / 100_000_000.0))
@@ -6,14 +6,35 @@ use serde::{Deserialize, Serialize};
 use crate::{
     flux,
     flux_api::{
-        authentication::get_zelidauth_or_trap, CONTENT_TYPE_TEXT_PLAIN_HEADER,
-        DEFAULT_HTTP_REQUEST_TIMEOUT_MS, FLUX_API_BASE_URL,
+        authentication::get_zelidauth_or_trap, flux_response_transform_context,
+        CONTENT_TYPE_TEXT_PLAIN_HEADER, DEFAULT_HTTPS_OUTCALL_POLICY,
+        DEFAULT_HTTP_REQUEST_RETRY_POLICY, DEFAULT_HTTP_REQUEST_TIMEOUT_MS, FLUX_API_BASE_URL,
+        ZELIDAUTH_HEADER_NAME,
+    },
+    http_over_ws::{
+        decode_json_response, execute_http_request, register_http_callback, HttpCallbackArgs,
+        HttpCallbackContext, HttpMethod, HttpRequestId, HttpRequestOptions, SensitiveData,
     },
-    http_over_ws::{execute_http_request, HttpMethod, HttpRequestId, HttpResponse},
     logger::log,
     sign_with_ecdsa, utils, NETWORK,
 };
 
+const CALCULATEPRICE_CALLBACK_NAME: &str = "flux_calculateprice";
+const APPREGISTER_CALLBACK_NAME: &str = "flux_appregister";
+const DEPLOYMENTINFORMATION_CALLBACK_NAME: &str = "flux_deploymentinformation";
+
+pub(super) fn register_http_callbacks() {
+    register_http_callback(CALCULATEPRICE_CALLBACK_NAME, |args| {
+        Box::pin(calculateprice_cb(args))
+    });
+    register_http_callback(APPREGISTER_CALLBACK_NAME, |args| {
+        Box::pin(appregister_cb(args))
+    });
+    register_http_callback(DEPLOYMENTINFORMATION_CALLBACK_NAME, |args| {
+        Box::pin(deploymentinformation_cb(args))
+    });
+}
+
 pub type ComposeSpec = GetAppPriceRequestComposeInner;
 
 pub struct DeploymentInfo {
@@ -32,6 +53,7 @@ pub struct DeploymentInfo {
 /// See https://docs.runonflux.io/#tag/Apps/operation/getAppPrice.
 pub fn calculate_app_price(deployment_info: DeploymentInfo) -> HttpRequestId {
     let calculateprice_url = FLUX_API_BASE_URL.join("/apps/calculateprice").unwrap();
+    let app_name = deployment_info.compose.name.clone();
 
     let body = GetAppPriceRequest {
         version: Some(7),
@@ -50,38 +72,82 @@ pub fn calculate_app_price(deployment_info: DeploymentInfo) -> HttpRequestId {
         staticip: Some(deployment_info.static_ip),
     };
 
-    async fn calculateprice_cb(res: HttpResponse) {
-        if res.status != 200 {
+    execute_http_request(
+        calculateprice_url,
+        HttpMethod::POST,
+        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
+        Some(serde_json::to_string(&body).unwrap()),
+        HttpRequestOptions {
+            callback: Some(HttpCallbackContext::with_candid_context(
+                CALCULATEPRICE_CALLBACK_NAME,
+                &app_name,
+            )),
+            timeout_ms: Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            retry_policy: Some(DEFAULT_HTTP_REQUEST_RETRY_POLICY),
+            https_outcall: Some(DEFAULT_HTTPS_OUTCALL_POLICY),
+            transform: Some(flux_response_transform_context()),
+            ..Default::default()
+        },
+    )
+}
+
+async fn calculateprice_cb(args: HttpCallbackArgs) {
+    let app_name: Option<String> = match args.decode_context() {
+        Ok(context) => context,
+        Err(err) => {
             log(&format!(
-                "calculateappprice failed with status: {}",
-                res.status
+                "calculateappprice callback has an invalid context: {}",
+                err
             ));
             return;
         }
+    };
 
-        let GetAppPrice200Response { status, data } = serde_json::from_slice(&res.body).unwrap();
-        if let Status::Error = status.unwrap() {
-            log(&format!("calculateappprice error: {:?}", data));
+    let res = match args.result {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "calculateappprice of app {:?} failed: {:?}",
+                app_name, err
+            ));
             return;
         }
+    };
 
-        log(&format!("calculateappprice response: {:?}", data));
+    if res.status != 200 {
+        log(&format!(
+            "calculateappprice failed with status: {}",
+            res.status
+        ));
+        return;
     }
 
-    execute_http_request(
-        calculateprice_url,
-        HttpMethod::POST,
-        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
-        Some(serde_json::to_string(&body).unwrap()),
-        Some(|res| Box::pin(calculateprice_cb(res))),
-        Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
-    )
+    let GetAppPrice200Response { status, data } = match decode_json_response(res) {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "calculateappprice response could not be decoded: {}",
+                err
+            ));
+            return;
+        }
+    };
+    if status != Some(Status::Success) {
+        log(&format!("calculateappprice error: {:?}", data));
+        return;
+    }
+
+    log(&format!(
+        "calculateappprice of app {:?} response: {:?}",
+        app_name, data
+    ));
 }
 
 /// See https://docs.runonflux.io/#tag/Apps/operation/Appregister.
 pub async fn register_app(deployment_info: DeploymentInfo) -> HttpRequestId {
     let zelidauth = get_zelidauth_or_trap();
     let appregister_url = FLUX_API_BASE_URL.join("/apps/appregister").unwrap();
+    let app_name = deployment_info.compose.name.clone();
 
     let mut body = AppregisterRequest {
         r#type: Some("fluxappregister".to_string()),
@@ -120,30 +186,74 @@ pub async fn register_app(deployment_info: DeploymentInfo) -> HttpRequestId {
 
     body.signature = Some(serde_json::Value::String(signature));
 
-    async fn appregister_cb(res: HttpResponse) {
-        if res.status != 200 {
-            log(&format!("appregister failed with status: {}", res.status));
+    execute_http_request(
+        appregister_url,
+        HttpMethod::POST,
+        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone(), zelidauth],
+        Some(serde_json::to_string(&body).unwrap()),
+        HttpRequestOptions {
+            callback: Some(HttpCallbackContext::with_candid_context(
+                APPREGISTER_CALLBACK_NAME,
+                &app_name,
+            )),
+            // this request can take longer to complete due to the sign_with_ecdsa in the callback
+            timeout_ms: Some(2 * DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            // registering an app is not idempotent, don't risk sending it twice
+            retry_policy: None,
+            transform: Some(flux_response_transform_context()),
+            sensitive: Some(SensitiveData::from_headers(&[ZELIDAUTH_HEADER_NAME])),
+            ..Default::default()
+        },
+    )
+}
+
+async fn appregister_cb(args: HttpCallbackArgs) {
+    let app_name: Option<String> = match args.decode_context() {
+        Ok(context) => context,
+        Err(err) => {
+            log(&format!(
+                "appregister callback has an invalid context: {}",
+                err
+            ));
             return;
         }
+    };
 
-        let Appregister200Response { status, data } = serde_json::from_slice(&res.body).unwrap();
-        if let Status::Error = status.unwrap() {
-            log(&format!("appregister error: {:?}", data));
+    let res = match args.result {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "appregister of app {:?} failed: {:?}",
+                app_name, err
+            ));
             return;
         }
+    };
 
-        log(&format!("appregister response: {:?}", data));
+    if res.status != 200 {
+        log(&format!("appregister failed with status: {}", res.status));
+        return;
     }
 
-    execute_http_request(
-        appregister_url,
-        HttpMethod::POST,
-        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone(), zelidauth],
-        Some(serde_json::to_string(&body).unwrap()),
-        Some(|res| Box::pin(appregister_cb(res))),
-        // this request can take longer to complete due to the sign_with_ecdsa in the callback
-        Some(2 * DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
-    )
+    let Appregister200Response { status, data } = match decode_json_response(res) {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "appregister response could not be decoded: {}",
+                err
+            ));
+            return;
+        }
+    };
+    if status != Some(Status::Success) {
+        log(&format!("appregister error: {:?}", data));
+        return;
+    }
+
+    log(&format!(
+        "appregister of app {:?} response: {:?}",
+        app_name, data
+    ));
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -167,34 +277,64 @@ pub fn fetch_deployment_information() -> HttpRequestId {
         .join("/apps/deploymentinformation")
         .unwrap();
 
-    async fn deploymentinformation_cb(res: HttpResponse) {
-        if res.status != 200 {
-            log(&format!(
-                "deploymentinformation failed with status: {}",
-                res.status
-            ));
-            return;
-        }
+    execute_http_request(
+        deploymentinformation_url,
+        HttpMethod::GET,
+        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
+        None,
+        HttpRequestOptions {
+            callback: Some(HttpCallbackContext::from_name(
+                DEPLOYMENTINFORMATION_CALLBACK_NAME,
+                vec![],
+            )),
+            timeout_ms: Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
+            retry_policy: Some(DEFAULT_HTTP_REQUEST_RETRY_POLICY),
+            https_outcall: Some(DEFAULT_HTTPS_OUTCALL_POLICY),
+            transform: Some(flux_response_transform_context()),
+            ..Default::default()
+        },
+    )
+}
 
-        let DeploymentInformationResponse { status, data } =
-            serde_json::from_slice(&res.body).unwrap();
-        if let Status::Error = status.unwrap() {
-            log(&format!("deploymentinformation error: {:?}", data));
+async fn deploymentinformation_cb(args: HttpCallbackArgs) {
+    let res = match args.result {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!("deploymentinformation failed: {:?}", err));
             return;
         }
+    };
 
+    if res.status != 200 {
         log(&format!(
-            "deploymentinformation address: {:?}",
-            data.unwrap().address
+            "deploymentinformation failed with status: {}",
+            res.status
         ));
+        return;
     }
 
-    execute_http_request(
-        deploymentinformation_url,
-        HttpMethod::GET,
-        vec![CONTENT_TYPE_TEXT_PLAIN_HEADER.deref().clone()],
-        None,
-        Some(|res| Box::pin(deploymentinformation_cb(res))),
-        Some(DEFAULT_HTTP_REQUEST_TIMEOUT_MS),
-    )
+    let DeploymentInformationResponse { status, data } = match decode_json_response(res) {
+        Ok(res) => res,
+        Err(err) => {
+            log(&format!(
+                "deploymentinformation response could not be decoded: {}",
+                err
+            ));
+            return;
+        }
+    };
+    if status != Some(Status::Success) {
+        log(&format!("deploymentinformation error: {:?}", data));
+        return;
+    }
+
+    let Some(data) = data else {
+        log("deploymentinformation response has no data");
+        return;
+    };
+
+    log(&format!(
+        "deploymentinformation address: {:?}",
+        data.address
+    ));
 }
@@ -5,7 +5,13 @@ use ic_cdk::trap;
 use lazy_static::lazy_static;
 use url::Url;
 
-use crate::{http_over_ws::HttpHeader, logger::log};
+use crate::{
+    http_over_ws::{
+        decode_http_response, register_http_transform, HttpHeader, HttpRequestRetryPolicy,
+        HttpResponse, HttpTransformArgs, HttpTransformContext, HttpsOutcallPolicy,
+    },
+    logger::log,
+};
 
 pub mod authentication;
 pub mod balance;
@@ -13,8 +19,26 @@ pub mod deployment;
 
 const DEFAULT_HTTP_REQUEST_TIMEOUT_MS: u64 = 15_000;
 
+const DEFAULT_HTTP_REQUEST_RETRY_POLICY: HttpRequestRetryPolicy = HttpRequestRetryPolicy {
+    max_attempts: 3,
+    initial_backoff_ms: 1_000,
+    retry_on_timeout: true,
+    retry_on_client_error: true,
+    retry_on_client_disconnect: true,
+};
+
+/// Used only by idempotent requests with deterministic responses,
+/// since HTTPS outcalls are executed by every replica of the subnet.
+const DEFAULT_HTTPS_OUTCALL_POLICY: HttpsOutcallPolicy = HttpsOutcallPolicy {
+    always: false,
+    max_response_bytes: Some(64 * 1024),
+    cycles: None,
+};
+
 const ZELIDAUTH_HEADER_NAME: &str = "zelidauth";
 
+const FLUX_RESPONSE_TRANSFORM_NAME: &str = "flux_response";
+
 lazy_static! {
     static ref FLUX_API_BASE_URL: Url = Url::parse("https://api.runonflux.io").unwrap();
     static ref CONTENT_TYPE_TEXT_PLAIN_HEADER: HttpHeader = HttpHeader {
@@ -23,6 +47,54 @@ lazy_static! {
     };
 }
 
+/// Decompresses the body, drops the headers, except the comma-separated ones in the context,
+/// and rejects successful responses that are not valid JSON, so that callbacks can parse them safely.
+fn flux_response_transform(args: HttpTransformArgs) -> Result<HttpResponse, String> {
+    let HttpResponse {
+        status,
+        headers,
+        body,
+    } = decode_http_response(args.response).map_err(|e| e.to_string())?;
+
+    if status == 200 {
+        serde_json::from_slice::<serde_json::Value>(&body)
+            .map_err(|e| format!("invalid JSON body: {}", e))?;
+    }
+
+    let kept_headers = String::from_utf8_lossy(&args.context).to_string();
+    let headers = headers
+        .into_iter()
+        .filter(|h| {
+            kept_headers
+                .split(',')
+                .any(|name| name.trim().eq_ignore_ascii_case(&h.name))
+        })
+        .collect();
+
+    Ok(HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// Flux callbacks don't need any header.
+fn flux_response_transform_context() -> HttpTransformContext {
+    HttpTransformContext::from_name(FLUX_RESPONSE_TRANSFORM_NAME, vec![])
+}
+
+/// Must be called on every (re)install, see [register_http_transform].
+pub fn register_http_transforms() {
+    register_http_transform(FLUX_RESPONSE_TRANSFORM_NAME, flux_response_transform);
+}
+
+/// Must be called on every (re)install, see [register_http_callback](crate::http_over_ws::register_http_callback).
+pub fn register_http_callbacks() {
+    authentication::register_http_callbacks();
+    balance::register_http_callbacks();
+    deployment::register_http_callbacks();
+}
+
 thread_local! {
     /* flexible */ static FLUX_STATE: RefCell<FluxState> = RefCell::default();
 }
@@ -40,11 +112,11 @@ impl FluxState {
             value: zelid_auth.clone(),
         });
 
-        log(&format!("set zelid auth header: {}", zelid_auth));
+        log("set zelid auth header");
     }
 
     fn set_auth_header_from_verifylogin_response_data(&mut self, data: VerifyLogin200ResponseData) {
-        log(&format!("verifylogin response: {:?}", data));
+        log(&format!("verifylogin response for zelid: {:?}", data.zelid));
 
         let zelid = data.zelid.unwrap();
         let login_phrase = data.login_phrase.unwrap();
@@ -0,0 +1,198 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{caller, query, update};
+use url::Url;
+
+use crate::utils::{caller_is_controller, get_current_timestamp_ms};
+
+use super::{
+    execute_http_request, HttpHeader, HttpMethod, HttpRequestId, HttpRequestOptions, SensitiveData,
+};
+
+/// How many requests a caller can execute in a time window.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct CallerQuota {
+    pub max_requests: u64,
+    pub window_ms: u64,
+}
+
+/// What a caller allowed to use the `execute_http_request` endpoint can do.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct CallerPolicy {
+    /// No limit if not set.
+    pub quota: Option<CallerQuota>,
+    /// The domains the caller can send requests to, subdomains included.
+    /// Any domain if not set.
+    pub allowed_domains: Option<Vec<String>>,
+}
+
+impl CallerPolicy {
+    fn is_domain_allowed(&self, host: &str) -> bool {
+        self.allowed_domains.as_ref().is_none_or(|domains| {
+            domains.iter().any(|domain| {
+                let domain = domain.to_lowercase();
+                host == domain || host.ends_with(&format!(".{}", domain))
+            })
+        })
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct CallerUsage {
+    window_started_at_ms: u64,
+    requests_in_window: u64,
+    total_requests: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct AllowedCaller {
+    policy: CallerPolicy,
+    usage: CallerUsage,
+}
+
+impl AllowedCaller {
+    /// Counts the request against the quota, if there's room for it.
+    fn consume_quota(&mut self, now_ms: u64) -> bool {
+        if let Some(quota) = &self.policy.quota {
+            if now_ms.saturating_sub(self.usage.window_started_at_ms) >= quota.window_ms {
+                self.usage.window_started_at_ms = now_ms;
+                self.usage.requests_in_window = 0;
+            }
+
+            if self.usage.requests_in_window >= quota.max_requests {
+                return false;
+            }
+        }
+
+        self.usage.requests_in_window += 1;
+        self.usage.total_requests += 1;
+        true
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum ExecuteHttpRequestError {
+    CallerNotAllowed,
+    QuotaExceeded,
+    InvalidUrl(String),
+    DomainNotAllowed(String),
+    /// The transform function is not a method of the caller.
+    InvalidTransform(String),
+}
+
+thread_local! {
+    /* stable */ static ALLOWED_CALLERS: RefCell<BTreeMap<Principal, AllowedCaller>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn allowed_callers() -> BTreeMap<Principal, AllowedCaller> {
+    ALLOWED_CALLERS.with(|callers| callers.borrow().clone())
+}
+
+pub fn set_allowed_callers(allowed_callers: BTreeMap<Principal, AllowedCaller>) {
+    ALLOWED_CALLERS.with(|callers| *callers.borrow_mut() = allowed_callers);
+}
+
+/// Checks the caller's policy and counts the request against its quota.
+///
+/// Only `http` and `https` URLs are accepted, from anyone.
+/// Other than that, controllers are not subject to any policy.
+pub fn authorize_caller(caller: Principal, url: &Url) -> Result<(), ExecuteHttpRequestError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(ExecuteHttpRequestError::InvalidUrl(format!(
+            "unsupported scheme {}",
+            url.scheme()
+        )));
+    }
+
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+
+    ALLOWED_CALLERS.with(|callers| {
+        let mut callers = callers.borrow_mut();
+        let allowed_caller = callers
+            .get_mut(&caller)
+            .ok_or(ExecuteHttpRequestError::CallerNotAllowed)?;
+
+        let host = url.host_str().unwrap_or_default();
+        if !allowed_caller.policy.is_domain_allowed(host) {
+            return Err(ExecuteHttpRequestError::DomainNotAllowed(host.to_string()));
+        }
+
+        if !allowed_caller.consume_quota(get_current_timestamp_ms()) {
+            return Err(ExecuteHttpRequestError::QuotaExceeded);
+        }
+
+        Ok(())
+    })
+}
+
+/// Lets other canisters and users execute HTTP requests through the connected clients.
+///
+/// Only the allowed callers and the controllers can call it.
+#[update(name = "execute_http_request")]
+fn execute_http_request_endpoint(
+    url: String,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<String>,
+    timeout_ms: Option<u64>,
+    sensitive: Option<SensitiveData>,
+) -> Result<HttpRequestId, ExecuteHttpRequestError> {
+    let url = Url::parse(&url).map_err(|e| ExecuteHttpRequestError::InvalidUrl(e.to_string()))?;
+
+    let requester = caller();
+
+    authorize_caller(requester, &url)?;
+
+    Ok(execute_http_request(
+        url,
+        method,
+        headers,
+        body,
+        HttpRequestOptions {
+            timeout_ms,
+            requester: Some(requester),
+            sensitive,
+            ..Default::default()
+        },
+    ))
+}
+
+#[query]
+fn get_allowed_callers() -> Vec<(Principal, AllowedCaller)> {
+    ALLOWED_CALLERS.with(|callers| {
+        callers
+            .borrow()
+            .iter()
+            .map(|(principal, c)| (*principal, c.clone()))
+            .collect()
+    })
+}
+
+/// Allows the caller to use the `execute_http_request` endpoint, or updates its policy.
+/// The caller's usage is kept.
+#[update(guard = "caller_is_controller")]
+fn set_allowed_caller(principal: Principal, policy: CallerPolicy) {
+    ALLOWED_CALLERS.with(|callers| {
+        let mut callers = callers.borrow_mut();
+        match callers.get_mut(&principal) {
+            Some(allowed_caller) => allowed_caller.policy = policy,
+            None => {
+                callers.insert(
+                    principal,
+                    AllowedCaller {
+                        policy,
+                        usage: CallerUsage::default(),
+                    },
+                );
+            }
+        }
+    });
+}
+
+#[update(guard = "caller_is_controller")]
+fn remove_allowed_caller(principal: Principal) {
+    ALLOWED_CALLERS.with(|callers| callers.borrow_mut().remove(&principal));
+}
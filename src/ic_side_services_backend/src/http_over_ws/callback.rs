@@ -0,0 +1,94 @@
+use std::{cell::RefCell, collections::HashMap, future::Future, pin::Pin};
+
+use candid::{decode_one, encode_one, CandidType, Deserialize};
+
+use crate::logger::log;
+
+use super::{lifecycle::finish_http_request, HttpRequestId, HttpRequestResult};
+
+pub struct HttpCallbackArgs {
+    pub request_id: HttpRequestId,
+    /// The final outcome of the request.
+    pub result: HttpRequestResult,
+    pub context: Vec<u8>,
+}
+
+impl HttpCallbackArgs {
+    /// Decodes a context created with [HttpCallbackContext::with_candid_context].
+    pub fn decode_context<T>(&self) -> Result<T, String>
+    where
+        T: CandidType + for<'de> Deserialize<'de>,
+    {
+        decode_one(&self.context).map_err(|e| e.to_string())
+    }
+}
+
+pub type HttpCallbackFunction = fn(HttpCallbackArgs) -> Pin<Box<dyn Future<Output = ()>>>;
+
+/// References a callback function by the name it was registered with,
+/// so that it can be stored along with the request and survive upgrades.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpCallbackContext {
+    pub function: String,
+    pub context: Vec<u8>,
+}
+
+impl HttpCallbackContext {
+    pub fn from_name(function: &str, context: Vec<u8>) -> Self {
+        HttpCallbackContext {
+            function: function.to_string(),
+            context,
+        }
+    }
+
+    /// The context can be decoded in the callback with [HttpCallbackArgs::decode_context].
+    pub fn with_candid_context<T: CandidType>(function: &str, context: &T) -> Self {
+        Self::from_name(function, encode_one(context).unwrap())
+    }
+}
+
+thread_local! {
+    /// Function pointers don't survive upgrades, so callbacks must be registered again on every (re)install.
+    /* flexible */ static HTTP_CALLBACKS: RefCell<HashMap<String, HttpCallbackFunction>> = RefCell::new(HashMap::new());
+}
+
+pub fn register_http_callback(name: &str, function: HttpCallbackFunction) {
+    HTTP_CALLBACKS.with(|callbacks| callbacks.borrow_mut().insert(name.to_string(), function));
+}
+
+pub fn run_http_callback(
+    request_id: HttpRequestId,
+    callback_context: HttpCallbackContext,
+    result: HttpRequestResult,
+) {
+    ic_cdk::spawn(async move {
+        call_http_callback(request_id, callback_context, result).await;
+
+        finish_http_request(request_id);
+    });
+}
+
+/// Calls the function referenced by the context, without finishing the request,
+/// so that a callback can hand the outcome over to another one.
+pub async fn call_http_callback(
+    request_id: HttpRequestId,
+    callback_context: HttpCallbackContext,
+    result: HttpRequestResult,
+) {
+    let Some(function) = HTTP_CALLBACKS
+        .with(|callbacks| callbacks.borrow().get(&callback_context.function).cloned())
+    else {
+        log(&format!(
+            "http_over_ws: callback function {} is not registered",
+            callback_context.function
+        ));
+        return;
+    };
+
+    function(HttpCallbackArgs {
+        request_id,
+        result,
+        context: callback_context.context,
+    })
+    .await;
+}
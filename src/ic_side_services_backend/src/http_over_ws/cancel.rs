@@ -0,0 +1,102 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::{caller, update};
+
+use crate::{logger::log, utils::get_current_timestamp_ns, ws::send_ws_message};
+
+use super::{
+    chunks::discard_chunked_response, complete_http_request, dispatch_pending_http_requests,
+    HttpOverWsMessage, HttpRequestFailureReason, HttpRequestId, CONNECTED_CLIENTS, HTTP_REQUESTS,
+    PENDING_HTTP_REQUESTS,
+};
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum CancelHttpRequestError {
+    NotFound,
+    AlreadyCompleted,
+    /// Only the requester and the controllers can cancel a request.
+    NotAllowed,
+}
+
+/// Stops the request and completes it with [HttpRequestFailureReason::Cancelled].
+///
+/// The clients executing it are freed and asked to abort it.
+/// Their late responses, as well as the one of an HTTPS outcall in progress, are ignored.
+pub fn cancel_http_request(request_id: HttpRequestId) -> Result<(), CancelHttpRequestError> {
+    abort_http_request(request_id, HttpRequestFailureReason::Cancelled)
+}
+
+/// Like [cancel_http_request], but completes the request with the given failure.
+pub fn abort_http_request(
+    request_id: HttpRequestId,
+    failure_reason: HttpRequestFailureReason,
+) -> Result<(), CancelHttpRequestError> {
+    HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+        let r = http_requests
+            .get_mut(&request_id)
+            .ok_or(CancelHttpRequestError::NotFound)?;
+
+        if r.completed_at.is_some() {
+            return Err(CancelHttpRequestError::AlreadyCompleted);
+        }
+
+        // covers the attempt timeout, the retry backoff and the pending deadline
+        r.clear_timer();
+        if let Some(q) = r.quorum.as_mut() {
+            q.pending_clients.clear();
+        }
+        let now = get_current_timestamp_ns();
+        for attempt in r.attempts.iter_mut().filter(|a| a.ended_at.is_none()) {
+            attempt.ended_at = Some(now);
+            attempt.failure_reason = Some(failure_reason.clone());
+        }
+
+        Ok(())
+    })?;
+
+    PENDING_HTTP_REQUESTS.with(|pending| pending.borrow_mut().retain(|id| *id != request_id));
+
+    let released_clients =
+        CONNECTED_CLIENTS.with(|clients| clients.borrow_mut().release_request(request_id));
+
+    for client_principal in released_clients {
+        discard_chunked_response(client_principal, request_id);
+
+        let supports_cancellation = CONNECTED_CLIENTS.with(|clients| {
+            clients
+                .borrow()
+                .client_capabilities(&client_principal)
+                .supports_cancellation()
+        });
+        if supports_cancellation {
+            send_ws_message(client_principal, HttpOverWsMessage::Cancel(request_id));
+        }
+    }
+
+    log(&format!(
+        "http_over_ws: aborted HTTP request {}: {:?}",
+        request_id, failure_reason
+    ));
+
+    complete_http_request(request_id, Err(failure_reason));
+
+    dispatch_pending_http_requests();
+
+    Ok(())
+}
+
+#[update(name = "cancel_http_request")]
+fn cancel_http_request_endpoint(request_id: HttpRequestId) -> Result<(), CancelHttpRequestError> {
+    let is_allowed = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .map(|r| r.is_cancellable_by(&caller()))
+    });
+
+    match is_allowed {
+        None => Err(CancelHttpRequestError::NotFound),
+        Some(false) => Err(CancelHttpRequestError::NotAllowed),
+        Some(true) => cancel_http_request(request_id),
+    }
+}
@@ -0,0 +1,373 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Deserialize};
+use ic_websocket_cdk::ClientPrincipal;
+use sha2::{Digest, Sha256};
+
+use crate::ws::send_ws_message;
+
+use super::{HttpOverWsMessage, HttpRequest, HttpRequestId, HttpResponse};
+
+/// How many messages `ws_get_messages` returns at once, the default of ic-websocket-cdk.
+const MAX_RETURNED_MESSAGES: usize = 50;
+/// The size limit of the query responses.
+const MAX_QUERY_RESPONSE_BYTES: usize = 2 * 1024 * 1024;
+/// Bodies larger than this are split into chunks of this size,
+/// so that a full batch of messages polled by the gateway fits in a query response,
+/// with room left for the headers and the certificate.
+pub const MAX_CHUNK_BYTES: usize = 32 * 1024;
+
+const _: () = assert!(MAX_CHUNK_BYTES * MAX_RETURNED_MESSAGES < MAX_QUERY_RESPONSE_BYTES);
+/// The largest body that can be reassembled from chunks.
+const MAX_CHUNKED_BODY_BYTES: u64 = 32 * 1024 * 1024;
+
+/// Sent in place of a body that is transferred in [HttpOverWsMessage::BodyChunk]s.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ChunkedBody {
+    pub total_length: u64,
+    pub chunks_count: u32,
+    /// The SHA-256 hash of the whole body.
+    pub sha256: Vec<u8>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct BodyChunk {
+    /// Starts from 0.
+    pub index: u32,
+    pub data: Vec<u8>,
+}
+
+/// A response whose body chunks are being received.
+struct IncomingChunkedResponse {
+    response: HttpResponse,
+    body: ChunkedBody,
+    chunks: BTreeMap<u32, Vec<u8>>,
+    received_bytes: u64,
+}
+
+impl IncomingChunkedResponse {
+    fn new(response: HttpResponse, body: ChunkedBody) -> Result<Self, String> {
+        if body.total_length > MAX_CHUNKED_BODY_BYTES {
+            return Err(format!(
+                "chunked body of {} bytes exceeds the limit of {} bytes",
+                body.total_length, MAX_CHUNKED_BODY_BYTES
+            ));
+        }
+
+        Ok(Self {
+            response,
+            body,
+            chunks: BTreeMap::new(),
+            received_bytes: 0,
+        })
+    }
+
+    fn add_chunk(&mut self, chunk: BodyChunk) -> Result<(), String> {
+        if chunk.index >= self.body.chunks_count {
+            return Err(format!(
+                "chunk {} out of range, expected {} chunks",
+                chunk.index, self.body.chunks_count
+            ));
+        }
+        if self.chunks.contains_key(&chunk.index) {
+            return Err(format!("chunk {} received twice", chunk.index));
+        }
+
+        self.received_bytes += chunk.data.len() as u64;
+        if self.received_bytes > self.body.total_length {
+            return Err(format!(
+                "chunks exceed the declared length of {} bytes",
+                self.body.total_length
+            ));
+        }
+
+        self.chunks.insert(chunk.index, chunk.data);
+        Ok(())
+    }
+
+    fn is_complete(&self) -> bool {
+        self.chunks.len() as u32 == self.body.chunks_count
+    }
+
+    /// Concatenates the chunks and checks the body against its declared length and hash.
+    fn assemble(self) -> Result<HttpResponse, String> {
+        let body: Vec<u8> = self.chunks.into_values().flatten().collect();
+
+        if body.len() as u64 != self.body.total_length {
+            return Err(format!(
+                "body is {} bytes, expected {}",
+                body.len(),
+                self.body.total_length
+            ));
+        }
+        if Sha256::digest(&body).as_slice() != self.body.sha256.as_slice() {
+            return Err(String::from("body hash mismatch"));
+        }
+
+        Ok(HttpResponse {
+            body,
+            ..self.response
+        })
+    }
+}
+
+thread_local! {
+    /* flexible */ static INCOMING_CHUNKED_RESPONSES: RefCell<BTreeMap<(ClientPrincipal, HttpRequestId), IncomingChunkedResponse>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+fn split_body(body: &[u8]) -> (ChunkedBody, Vec<BodyChunk>) {
+    let chunks: Vec<BodyChunk> = body
+        .chunks(MAX_CHUNK_BYTES)
+        .enumerate()
+        .map(|(index, data)| BodyChunk {
+            index: index as u32,
+            data: data.to_vec(),
+        })
+        .collect();
+
+    (
+        ChunkedBody {
+            total_length: body.len() as u64,
+            chunks_count: chunks.len() as u32,
+            sha256: Sha256::digest(body).to_vec(),
+        },
+        chunks,
+    )
+}
+
+/// Sends the request to the client, splitting its body into chunks if it's too large.
+pub fn send_http_request(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+    http_request: HttpRequest,
+) {
+    let body_len = http_request.body.as_ref().map_or(0, |body| body.len());
+    if body_len <= MAX_CHUNK_BYTES {
+        send_ws_message(
+            client_principal,
+            HttpOverWsMessage::HttpRequest(request_id, http_request),
+        );
+        return;
+    }
+
+    let body = http_request.body.clone().unwrap_or_default();
+    let (chunked_body, chunks) = split_body(&body);
+
+    send_ws_message(
+        client_principal,
+        HttpOverWsMessage::ChunkedHttpRequest(
+            request_id,
+            HttpRequest {
+                body: None,
+                ..http_request
+            },
+            chunked_body,
+        ),
+    );
+
+    for chunk in chunks {
+        send_ws_message(
+            client_principal,
+            HttpOverWsMessage::BodyChunk(request_id, chunk),
+        );
+    }
+}
+
+/// Called when the client announces a response whose body follows in chunks.
+///
+/// Returns the response right away if the body is empty.
+pub fn start_chunked_response(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+    response: HttpResponse,
+    body: ChunkedBody,
+) -> Result<Option<HttpResponse>, String> {
+    let incoming = IncomingChunkedResponse::new(response, body)?;
+
+    if incoming.is_complete() {
+        return incoming.assemble().map(Some);
+    }
+
+    INCOMING_CHUNKED_RESPONSES.with(|responses| {
+        responses
+            .borrow_mut()
+            .insert((client_principal, request_id), incoming)
+    });
+
+    Ok(None)
+}
+
+/// Adds the chunk to the response being received from the client.
+///
+/// Returns the response once all its chunks have been received,
+/// or an error if the chunk or the reassembled body are invalid.
+/// Chunks of responses that are not being received are ignored.
+pub fn receive_response_chunk(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+    chunk: BodyChunk,
+) -> Result<Option<HttpResponse>, String> {
+    let key = (client_principal, request_id);
+
+    INCOMING_CHUNKED_RESPONSES.with(|responses| {
+        let mut responses = responses.borrow_mut();
+        let Some(incoming) = responses.get_mut(&key) else {
+            return Ok(None);
+        };
+
+        if let Err(err) = incoming.add_chunk(chunk) {
+            responses.remove(&key);
+            return Err(err);
+        }
+
+        if !incoming.is_complete() {
+            return Ok(None);
+        }
+
+        responses.remove(&key).unwrap().assemble().map(Some)
+    })
+}
+
+/// Drops the chunks received so far, if any.
+pub fn discard_chunked_response(client_principal: ClientPrincipal, request_id: HttpRequestId) {
+    INCOMING_CHUNKED_RESPONSES.with(|responses| {
+        responses
+            .borrow_mut()
+            .remove(&(client_principal, request_id))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> HttpResponse {
+        HttpResponse {
+            status: 200u32.into(),
+            headers: vec![],
+            body: vec![],
+        }
+    }
+
+    fn body(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn receive(body: ChunkedBody, chunks: Vec<BodyChunk>) -> Result<HttpResponse, String> {
+        let mut incoming = IncomingChunkedResponse::new(response(), body)?;
+        for chunk in chunks {
+            incoming.add_chunk(chunk)?;
+        }
+        assert!(incoming.is_complete());
+
+        incoming.assemble()
+    }
+
+    #[test]
+    fn split_body_into_chunks() {
+        let body = body(2 * MAX_CHUNK_BYTES + 10);
+
+        let (chunked_body, chunks) = split_body(&body);
+
+        assert_eq!(chunked_body.total_length, body.len() as u64);
+        assert_eq!(chunked_body.chunks_count, 3);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].data.len(), MAX_CHUNK_BYTES);
+        assert_eq!(chunks[2].index, 2);
+        assert_eq!(chunks[2].data.len(), 10);
+    }
+
+    #[test]
+    fn split_body_of_exactly_one_chunk() {
+        let (chunked_body, chunks) = split_body(&body(MAX_CHUNK_BYTES));
+
+        assert_eq!(chunked_body.chunks_count, 1);
+        assert_eq!(chunks.len(), 1);
+    }
+
+    #[test]
+    fn reassemble_chunks_in_any_order() {
+        let body = body(3 * MAX_CHUNK_BYTES - 1);
+        let (chunked_body, mut chunks) = split_body(&body);
+        chunks.reverse();
+
+        let response = receive(chunked_body, chunks).unwrap();
+
+        assert_eq!(response.body, body);
+        assert_eq!(response.status, 200u32);
+    }
+
+    #[test]
+    fn empty_body_is_complete_right_away() {
+        let (chunked_body, chunks) = split_body(&[]);
+        assert_eq!(chunked_body.chunks_count, 0);
+        assert!(chunks.is_empty());
+
+        let incoming = IncomingChunkedResponse::new(response(), chunked_body).unwrap();
+
+        assert!(incoming.is_complete());
+        assert!(incoming.assemble().unwrap().body.is_empty());
+    }
+
+    #[test]
+    fn reject_chunk_out_of_range() {
+        let (chunked_body, mut chunks) = split_body(&body(MAX_CHUNK_BYTES + 1));
+        chunks[1].index = 2;
+
+        assert!(receive(chunked_body, chunks)
+            .unwrap_err()
+            .contains("out of range"));
+    }
+
+    #[test]
+    fn reject_chunk_received_twice() {
+        let (chunked_body, chunks) = split_body(&body(MAX_CHUNK_BYTES + 1));
+        let chunks = vec![chunks[0].clone(), chunks[0].clone()];
+
+        assert!(receive(chunked_body, chunks)
+            .unwrap_err()
+            .contains("received twice"));
+    }
+
+    #[test]
+    fn reject_chunks_exceeding_declared_length() {
+        let (mut chunked_body, chunks) = split_body(&body(MAX_CHUNK_BYTES + 1));
+        chunked_body.total_length -= 1;
+
+        assert!(receive(chunked_body, chunks)
+            .unwrap_err()
+            .contains("exceed the declared length"));
+    }
+
+    #[test]
+    fn reject_body_shorter_than_declared() {
+        let (mut chunked_body, chunks) = split_body(&body(MAX_CHUNK_BYTES + 1));
+        chunked_body.total_length += 1;
+
+        assert!(receive(chunked_body, chunks)
+            .unwrap_err()
+            .contains("expected"));
+    }
+
+    #[test]
+    fn reject_body_hash_mismatch() {
+        let (chunked_body, mut chunks) = split_body(&body(MAX_CHUNK_BYTES + 1));
+        chunks[1].data[0] ^= 1;
+
+        assert_eq!(
+            receive(chunked_body, chunks).unwrap_err(),
+            "body hash mismatch"
+        );
+    }
+
+    #[test]
+    fn reject_body_exceeding_limit() {
+        let chunked_body = ChunkedBody {
+            total_length: MAX_CHUNKED_BODY_BYTES + 1,
+            chunks_count: 1,
+            sha256: vec![],
+        };
+
+        assert!(IncomingChunkedResponse::new(response(), chunked_body).is_err());
+    }
+}
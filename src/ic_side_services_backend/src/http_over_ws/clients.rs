@@ -0,0 +1,764 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use candid::{CandidType, Deserialize};
+use ic_websocket_cdk::ClientPrincipal;
+
+use super::{handshake::ExecutorCapabilities, HttpRequest, HttpRequestId};
+
+/// How much a new latency sample weighs in the moving average, in percent.
+const LATENCY_SMOOTHING_PERCENT: u64 = 20;
+/// How many of the latest outcomes and latencies are used for the score and the percentiles.
+const STATS_WINDOW: usize = 100;
+/// Below this many outcomes, the client's score is not reliable and is considered perfect.
+const MIN_OUTCOMES_FOR_SCORE: usize = 10;
+/// How many of the latest requests the clients were freed from are remembered.
+const MAX_ABANDONED_REQUESTS: usize = 1_000;
+
+/// How [ConnectedClients::assign_request] picks the client to send a request to.
+#[derive(CandidType, Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum SchedulingStrategy {
+    /// The client with the fewest requests in flight.
+    #[default]
+    LeastInFlight,
+    /// Clients take turns, proportionally to their weight.
+    WeightedRoundRobin,
+    /// The client with the lowest observed response time,
+    /// scaled by the requests it already has in flight.
+    LatencyAware,
+}
+
+/// Scheduling parameters that apply to a single client.
+///
+/// They are kept by principal, so that they survive reconnections.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ClientSchedulingParams {
+    /// Used by [SchedulingStrategy::WeightedRoundRobin]. Defaults to 1.
+    pub weight: Option<u32>,
+    /// Overrides [SchedulingConfig::default_max_concurrent_requests].
+    pub max_concurrent_requests: Option<u32>,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct SchedulingConfig {
+    pub strategy: SchedulingStrategy,
+    /// A client with this many requests in flight doesn't receive new ones.
+    /// No limit if not set.
+    pub default_max_concurrent_requests: Option<u32>,
+    pub client_params: HashMap<ClientPrincipal, ClientSchedulingParams>,
+    /// Clients with a lower score only receive requests when no other client is available.
+    pub deprioritize_below_score: Option<u32>,
+    /// Clients with a lower score are disconnected.
+    pub disconnect_below_score: Option<u32>,
+}
+
+impl SchedulingConfig {
+    fn weight(&self, client_principal: &ClientPrincipal) -> u32 {
+        self.client_params
+            .get(client_principal)
+            .and_then(|p| p.weight)
+            .unwrap_or(1)
+    }
+
+    fn max_concurrent_requests(&self, client_principal: &ClientPrincipal) -> Option<u32> {
+        self.client_params
+            .get(client_principal)
+            .and_then(|p| p.max_concurrent_requests)
+            .or(self.default_max_concurrent_requests)
+    }
+}
+
+pub enum ClientOutcome {
+    Completed,
+    Failed,
+    TimedOut,
+    /// The client's response disagreed with the quorum.
+    /// Counted on top of [ClientOutcome::Completed], since the client did respond.
+    QuorumDisagreement,
+}
+
+/// Kept by principal, so that they survive reconnections.
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct ClientStats {
+    completed_requests: u64,
+    failed_requests: u64,
+    timed_out_requests: u64,
+    quorum_disagreements: u64,
+    /// Messages that were rejected, e.g. because they could not be decoded.
+    protocol_errors: u64,
+    last_seen_at_ms: Option<u64>,
+    /// `true` for the successful ones.
+    recent_outcomes: VecDeque<bool>,
+    recent_latencies_ms: VecDeque<u64>,
+}
+
+impl ClientStats {
+    fn record_outcome(&mut self, outcome: ClientOutcome) {
+        let is_success = match outcome {
+            ClientOutcome::Completed => {
+                self.completed_requests += 1;
+                true
+            }
+            ClientOutcome::Failed => {
+                self.failed_requests += 1;
+                false
+            }
+            ClientOutcome::TimedOut => {
+                self.timed_out_requests += 1;
+                false
+            }
+            ClientOutcome::QuorumDisagreement => {
+                self.quorum_disagreements += 1;
+                false
+            }
+        };
+
+        self.recent_outcomes.push_back(is_success);
+        if self.recent_outcomes.len() > STATS_WINDOW {
+            self.recent_outcomes.pop_front();
+        }
+    }
+
+    fn record_latency(&mut self, latency_ms: u64) {
+        self.recent_latencies_ms.push_back(latency_ms);
+        if self.recent_latencies_ms.len() > STATS_WINDOW {
+            self.recent_latencies_ms.pop_front();
+        }
+    }
+
+    /// The percentage of successful outcomes among the recent ones.
+    pub fn score(&self) -> u32 {
+        if self.recent_outcomes.len() < MIN_OUTCOMES_FOR_SCORE {
+            return 100;
+        }
+
+        let successes = self.recent_outcomes.iter().filter(|s| **s).count();
+        (successes * 100 / self.recent_outcomes.len()) as u32
+    }
+
+    fn latency_percentile_ms(&self, percentile: usize) -> Option<u64> {
+        let mut latencies: Vec<u64> = self.recent_latencies_ms.iter().cloned().collect();
+        latencies.sort_unstable();
+
+        let index = (latencies.len() * percentile / 100).min(latencies.len().checked_sub(1)?);
+        latencies.get(index).cloned()
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ClientHealth {
+    pub connected: bool,
+    /// The percentage of successful requests among the latest ones,
+    /// 100 until there are enough of them.
+    pub score: u32,
+    pub completed_requests: u64,
+    pub failed_requests: u64,
+    pub timed_out_requests: u64,
+    pub quorum_disagreements: u64,
+    pub protocol_errors: u64,
+    pub last_seen_at_ms: Option<u64>,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p90_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct ConnectedClient {
+    /// The requests assigned to the client, with the time they were assigned at.
+    in_flight_requests: BTreeMap<HttpRequestId, u64>,
+    /// Moving average of the time the client takes to respond.
+    avg_latency_ms: Option<u64>,
+    /// Used by [SchedulingStrategy::WeightedRoundRobin].
+    current_weight: i64,
+}
+
+impl ConnectedClient {
+    fn in_flight_count(&self) -> u64 {
+        self.in_flight_requests.len() as u64
+    }
+
+    fn record_latency(&mut self, latency_ms: u64) {
+        self.avg_latency_ms = Some(match self.avg_latency_ms {
+            Some(avg) => {
+                (avg * (100 - LATENCY_SMOOTHING_PERCENT) + latency_ms * LATENCY_SMOOTHING_PERCENT)
+                    / 100
+            }
+            None => latency_ms,
+        });
+    }
+}
+
+#[derive(CandidType, Clone, Default, Deserialize)]
+pub struct ConnectedClients {
+    clients: BTreeMap<ClientPrincipal, ConnectedClient>,
+    scheduling_config: SchedulingConfig,
+    client_stats: BTreeMap<ClientPrincipal, ClientStats>,
+    /// Sent by the clients during the handshake, quarantined ones included.
+    client_capabilities: BTreeMap<ClientPrincipal, ExecutorCapabilities>,
+    /// The requests the clients were freed from before responding, oldest first,
+    /// e.g. because they timed out or were cancelled.
+    abandoned_requests: VecDeque<(ClientPrincipal, HttpRequestId)>,
+}
+
+impl ConnectedClients {
+    pub fn new() -> Self {
+        ConnectedClients::default()
+    }
+
+    pub fn add_client(&mut self, client_principal: ClientPrincipal) {
+        self.clients.entry(client_principal).or_default();
+    }
+
+    pub fn client_principals(&self) -> Vec<ClientPrincipal> {
+        self.clients.keys().cloned().collect()
+    }
+
+    pub fn set_client_capabilities(
+        &mut self,
+        client_principal: ClientPrincipal,
+        capabilities: ExecutorCapabilities,
+    ) {
+        self.client_capabilities
+            .insert(client_principal, capabilities);
+    }
+
+    /// The capabilities sent by the client, or the legacy ones if it didn't send any.
+    pub fn client_capabilities(&self, client_principal: &ClientPrincipal) -> ExecutorCapabilities {
+        self.client_capabilities
+            .get(client_principal)
+            .cloned()
+            .unwrap_or_else(ExecutorCapabilities::legacy)
+    }
+
+    /// Whether the client can execute the request, according to its capabilities.
+    fn supports(&self, client_principal: &ClientPrincipal, http_request: &HttpRequest) -> bool {
+        self.client_capabilities(client_principal)
+            .supports(http_request)
+    }
+
+    pub fn scheduling_config(&self) -> &SchedulingConfig {
+        &self.scheduling_config
+    }
+
+    pub fn set_scheduling_config(&mut self, scheduling_config: SchedulingConfig) {
+        self.scheduling_config = scheduling_config;
+    }
+
+    pub fn set_scheduling_strategy(&mut self, strategy: SchedulingStrategy) {
+        self.scheduling_config.strategy = strategy;
+    }
+
+    pub fn set_score_thresholds(
+        &mut self,
+        deprioritize_below_score: Option<u32>,
+        disconnect_below_score: Option<u32>,
+    ) {
+        self.scheduling_config.deprioritize_below_score = deprioritize_below_score;
+        self.scheduling_config.disconnect_below_score = disconnect_below_score;
+    }
+
+    pub fn set_default_max_concurrent_requests(&mut self, max_concurrent_requests: Option<u32>) {
+        self.scheduling_config.default_max_concurrent_requests = max_concurrent_requests;
+    }
+
+    pub fn set_client_scheduling_params(
+        &mut self,
+        client_principal: ClientPrincipal,
+        params: Option<ClientSchedulingParams>,
+    ) {
+        match params {
+            Some(params) => {
+                self.scheduling_config
+                    .client_params
+                    .insert(client_principal, params);
+            }
+            None => {
+                self.scheduling_config
+                    .client_params
+                    .remove(&client_principal);
+            }
+        }
+    }
+
+    fn assign_request_to_client(
+        &mut self,
+        client_principal: ClientPrincipal,
+        request_id: HttpRequestId,
+        now_ms: u64,
+    ) {
+        if let Some(client) = self.clients.get_mut(&client_principal) {
+            client.in_flight_requests.insert(request_id, now_ms);
+        }
+    }
+
+    fn has_capacity(&self, client_principal: &ClientPrincipal, client: &ConnectedClient) -> bool {
+        self.scheduling_config
+            .max_concurrent_requests(client_principal)
+            .map(|max| client.in_flight_count() < max as u64)
+            .unwrap_or(true)
+    }
+
+    /// Picks one of the candidates according to the configured [SchedulingStrategy].
+    fn pick_client(&mut self, candidates: &[ClientPrincipal]) -> Option<ClientPrincipal> {
+        match self.scheduling_config.strategy {
+            SchedulingStrategy::LeastInFlight => candidates
+                .iter()
+                .min_by_key(|c| self.clients[c].in_flight_count())
+                .cloned(),
+            SchedulingStrategy::WeightedRoundRobin => {
+                // smooth weighted round robin, as implemented by nginx
+                let mut total_weight = 0;
+                let mut selected: Option<(ClientPrincipal, i64)> = None;
+
+                for client_principal in candidates {
+                    let weight = self.scheduling_config.weight(client_principal) as i64;
+                    let client = self.clients.get_mut(client_principal)?;
+                    client.current_weight += weight;
+                    total_weight += weight;
+
+                    if selected.is_none_or(|(_, w)| client.current_weight > w) {
+                        selected = Some((*client_principal, client.current_weight));
+                    }
+                }
+
+                let (client_principal, _) = selected?;
+                if let Some(client) = self.clients.get_mut(&client_principal) {
+                    client.current_weight -= total_weight;
+                }
+
+                Some(client_principal)
+            }
+            SchedulingStrategy::LatencyAware => candidates
+                .iter()
+                .min_by_key(|c| {
+                    let client = &self.clients[c];
+                    // clients without measurements are preferred, so that they get measured
+                    let latency = client.avg_latency_ms.unwrap_or(0);
+                    (
+                        latency.saturating_mul(client.in_flight_count() + 1),
+                        client.in_flight_count(),
+                    )
+                })
+                .cloned(),
+        }
+    }
+
+    fn score(&self, client_principal: &ClientPrincipal) -> u32 {
+        self.client_stats
+            .get(client_principal)
+            .map(|stats| stats.score())
+            .unwrap_or(100)
+    }
+
+    fn is_deprioritized(&self, client_principal: &ClientPrincipal) -> bool {
+        self.scheduling_config
+            .deprioritize_below_score
+            .is_some_and(|min_score| self.score(client_principal) < min_score)
+    }
+
+    /// Picks one of the candidates, preferring the ones that are not deprioritized.
+    fn pick_healthy_client(&mut self, candidates: &[ClientPrincipal]) -> Option<ClientPrincipal> {
+        let (healthy, deprioritized): (Vec<ClientPrincipal>, Vec<ClientPrincipal>) = candidates
+            .iter()
+            .partition(|principal| !self.is_deprioritized(principal));
+
+        self.pick_client(&healthy)
+            .or_else(|| self.pick_client(&deprioritized))
+    }
+
+    /// Assigns the request to a compatible client with spare capacity,
+    /// preferring the healthy ones and the ones not in `excluded_clients`.
+    ///
+    /// Returns [None] if all compatible clients are saturated.
+    pub fn assign_request(
+        &mut self,
+        request_id: HttpRequestId,
+        http_request: &HttpRequest,
+        excluded_clients: &[ClientPrincipal],
+        now_ms: u64,
+    ) -> Option<ClientPrincipal> {
+        let (preferred, excluded): (Vec<ClientPrincipal>, Vec<ClientPrincipal>) = self
+            .clients
+            .iter()
+            .filter(|(principal, client)| {
+                self.has_capacity(principal, client) && self.supports(principal, http_request)
+            })
+            .map(|(principal, _)| *principal)
+            .partition(|principal| !excluded_clients.contains(principal));
+
+        let client_principal = self
+            .pick_healthy_client(&preferred)
+            // all clients have been excluded, fall back to any of them
+            .or_else(|| self.pick_healthy_client(&excluded))?;
+
+        self.assign_request_to_client(client_principal, request_id, now_ms);
+        Some(client_principal)
+    }
+
+    /// Assigns the request to up to `count` distinct compatible clients with spare capacity.
+    ///
+    /// Nothing is assigned if less than `min_count` clients are available.
+    pub fn assign_request_to_distinct_clients(
+        &mut self,
+        request_id: HttpRequestId,
+        http_request: &HttpRequest,
+        count: usize,
+        min_count: usize,
+        now_ms: u64,
+    ) -> Vec<ClientPrincipal> {
+        let mut assigned: Vec<ClientPrincipal> = vec![];
+
+        while assigned.len() < count {
+            let candidates: Vec<ClientPrincipal> = self
+                .clients
+                .iter()
+                .filter(|(principal, client)| {
+                    !assigned.contains(principal)
+                        && self.has_capacity(principal, client)
+                        && self.supports(principal, http_request)
+                })
+                .map(|(principal, _)| *principal)
+                .collect();
+
+            let Some(client_principal) = self.pick_healthy_client(&candidates) else {
+                break;
+            };
+
+            self.assign_request_to_client(client_principal, request_id, now_ms);
+            assigned.push(client_principal);
+        }
+
+        if assigned.len() < min_count {
+            for client_principal in assigned.drain(..) {
+                self.complete_request_for_client(client_principal, request_id, None);
+            }
+        }
+
+        assigned
+    }
+
+    /// The stats of the client, created on first use.
+    ///
+    /// Only the connected clients get stats, so that the principals
+    /// that are not admitted can't grow them without bound.
+    fn connected_client_stats(
+        &mut self,
+        client_principal: ClientPrincipal,
+    ) -> Option<&mut ClientStats> {
+        if !self.clients.contains_key(&client_principal) {
+            return None;
+        }
+
+        Some(self.client_stats.entry(client_principal).or_default())
+    }
+
+    pub fn mark_client_seen(&mut self, client_principal: ClientPrincipal, now_ms: u64) {
+        if let Some(stats) = self.connected_client_stats(client_principal) {
+            stats.last_seen_at_ms = Some(now_ms);
+        }
+    }
+
+    pub fn record_protocol_error(&mut self, client_principal: ClientPrincipal) {
+        if let Some(stats) = self.connected_client_stats(client_principal) {
+            stats.protocol_errors += 1;
+        }
+    }
+
+    /// Returns `true` if the client's score fell below
+    /// [SchedulingConfig::disconnect_below_score] and the client must be disconnected.
+    pub fn record_client_outcome(
+        &mut self,
+        client_principal: ClientPrincipal,
+        outcome: ClientOutcome,
+    ) -> bool {
+        let Some(stats) = self.connected_client_stats(client_principal) else {
+            return false;
+        };
+        stats.record_outcome(outcome);
+        let score = stats.score();
+
+        self.scheduling_config
+            .disconnect_below_score
+            .is_some_and(|min_score| score < min_score)
+    }
+
+    pub fn clients_health(&self) -> Vec<(ClientPrincipal, ClientHealth)> {
+        self.client_stats
+            .iter()
+            .map(|(client_principal, stats)| {
+                (
+                    *client_principal,
+                    ClientHealth {
+                        connected: self.clients.contains_key(client_principal),
+                        score: stats.score(),
+                        completed_requests: stats.completed_requests,
+                        failed_requests: stats.failed_requests,
+                        timed_out_requests: stats.timed_out_requests,
+                        quorum_disagreements: stats.quorum_disagreements,
+                        protocol_errors: stats.protocol_errors,
+                        last_seen_at_ms: stats.last_seen_at_ms,
+                        latency_p50_ms: stats.latency_percentile_ms(50),
+                        latency_p90_ms: stats.latency_percentile_ms(90),
+                        latency_p99_ms: stats.latency_percentile_ms(99),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    pub fn is_request_assigned_to_client(
+        &self,
+        client_principal: ClientPrincipal,
+        request_id: HttpRequestId,
+    ) -> bool {
+        self.clients
+            .get(&client_principal)
+            .map(|c| c.in_flight_requests.contains_key(&request_id))
+            .unwrap_or(false)
+    }
+
+    fn abandon_request(&mut self, client_principal: ClientPrincipal, request_id: HttpRequestId) {
+        self.abandoned_requests
+            .push_back((client_principal, request_id));
+        if self.abandoned_requests.len() > MAX_ABANDONED_REQUESTS {
+            self.abandoned_requests.pop_front();
+        }
+    }
+
+    /// Whether the client was recently freed from the request before responding,
+    /// in which case its late messages for the request are expected.
+    pub fn is_request_abandoned_by_client(
+        &self,
+        client_principal: ClientPrincipal,
+        request_id: HttpRequestId,
+    ) -> bool {
+        self.abandoned_requests
+            .contains(&(client_principal, request_id))
+    }
+
+    /// Frees the client from the request.
+    ///
+    /// If the client responded, the time it took is recorded for [SchedulingStrategy::LatencyAware].
+    /// Otherwise, the request is remembered as abandoned by the client.
+    pub fn complete_request_for_client(
+        &mut self,
+        client_principal: ClientPrincipal,
+        request_id: HttpRequestId,
+        responded_at_ms: Option<u64>,
+    ) {
+        if let Some(client) = self.clients.get_mut(&client_principal) {
+            let assigned_at_ms = client.in_flight_requests.remove(&request_id);
+
+            match (assigned_at_ms, responded_at_ms) {
+                (Some(assigned_at_ms), Some(responded_at_ms)) => {
+                    let latency_ms = responded_at_ms.saturating_sub(assigned_at_ms);
+                    client.record_latency(latency_ms);
+                    self.client_stats
+                        .entry(client_principal)
+                        .or_default()
+                        .record_latency(latency_ms);
+                }
+                (Some(_), None) => self.abandon_request(client_principal, request_id),
+                (None, _) => {}
+            }
+        };
+    }
+
+    /// Frees all the clients executing the request, and returns them.
+    ///
+    /// The request is remembered as abandoned by them.
+    pub fn release_request(&mut self, request_id: HttpRequestId) -> Vec<ClientPrincipal> {
+        let released_clients: Vec<ClientPrincipal> = self
+            .clients
+            .iter_mut()
+            .filter_map(|(client_principal, client)| {
+                client
+                    .in_flight_requests
+                    .remove(&request_id)
+                    .map(|_| *client_principal)
+            })
+            .collect();
+
+        for client_principal in &released_clients {
+            self.abandon_request(*client_principal, request_id);
+        }
+
+        released_clients
+    }
+
+    /// Removes the client and returns the requests it had in flight.
+    pub fn remove_client(&mut self, client_principal: &ClientPrincipal) -> Vec<HttpRequestId> {
+        self.client_capabilities.remove(client_principal);
+
+        self.clients
+            .remove(client_principal)
+            .map(|c| c.in_flight_requests.into_keys().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::super::HttpMethod;
+    use super::*;
+
+    fn client(id: u8) -> ClientPrincipal {
+        Principal::from_slice(&[id])
+    }
+
+    fn http_request() -> HttpRequest {
+        HttpRequest {
+            url: String::from("https://example.com"),
+            method: HttpMethod::GET,
+            headers: vec![],
+            body: None,
+        }
+    }
+
+    fn connected_clients(strategy: SchedulingStrategy, count: u8) -> ConnectedClients {
+        let mut clients = ConnectedClients::new();
+        clients.set_scheduling_strategy(strategy);
+        for id in 0..count {
+            clients.add_client(client(id));
+        }
+        clients
+    }
+
+    fn assign(clients: &mut ConnectedClients, request_id: HttpRequestId) -> ClientPrincipal {
+        clients
+            .assign_request(request_id, &http_request(), &[], 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn least_in_flight_picks_the_least_busy_client() {
+        let mut clients = connected_clients(SchedulingStrategy::LeastInFlight, 2);
+
+        let first = assign(&mut clients, 1);
+        let second = assign(&mut clients, 2);
+        assert_ne!(first, second);
+
+        clients.complete_request_for_client(second, 2, None);
+        assert_eq!(assign(&mut clients, 3), second);
+    }
+
+    #[test]
+    fn weighted_round_robin_follows_the_weights() {
+        let mut clients = connected_clients(SchedulingStrategy::WeightedRoundRobin, 2);
+        clients.set_client_scheduling_params(
+            client(0),
+            Some(ClientSchedulingParams {
+                weight: Some(2),
+                max_concurrent_requests: None,
+            }),
+        );
+
+        let picked: Vec<ClientPrincipal> = (0..6).map(|id| assign(&mut clients, id)).collect();
+
+        assert_eq!(
+            picked,
+            vec![
+                client(0),
+                client(1),
+                client(0),
+                client(0),
+                client(1),
+                client(0)
+            ]
+        );
+    }
+
+    #[test]
+    fn latency_aware_prefers_the_fastest_client() {
+        let mut clients = connected_clients(SchedulingStrategy::LatencyAware, 2);
+        clients.assign_request_to_client(client(0), 1, 0);
+        clients.complete_request_for_client(client(0), 1, Some(100));
+        clients.assign_request_to_client(client(1), 2, 0);
+        clients.complete_request_for_client(client(1), 2, Some(10));
+
+        assert_eq!(assign(&mut clients, 3), client(1));
+    }
+
+    #[test]
+    fn latency_aware_accounts_for_the_requests_in_flight() {
+        let mut clients = connected_clients(SchedulingStrategy::LatencyAware, 2);
+        clients.assign_request_to_client(client(0), 1, 0);
+        clients.complete_request_for_client(client(0), 1, Some(30));
+        clients.assign_request_to_client(client(1), 2, 0);
+        clients.complete_request_for_client(client(1), 2, Some(20));
+
+        // 20ms with one request in flight weighs more than 30ms with none
+        assert_eq!(assign(&mut clients, 3), client(1));
+        assert_eq!(assign(&mut clients, 4), client(0));
+    }
+
+    #[test]
+    fn saturated_clients_are_skipped() {
+        let mut clients = connected_clients(SchedulingStrategy::LeastInFlight, 1);
+        clients.set_default_max_concurrent_requests(Some(1));
+
+        assert_eq!(assign(&mut clients, 1), client(0));
+        assert_eq!(clients.assign_request(2, &http_request(), &[], 0), None);
+    }
+
+    #[test]
+    fn remember_the_requests_freed_before_responding() {
+        let mut clients = connected_clients(SchedulingStrategy::LeastInFlight, 2);
+
+        clients.assign_request_to_client(client(0), 1, 0);
+        clients.complete_request_for_client(client(0), 1, Some(10));
+        assert!(!clients.is_request_abandoned_by_client(client(0), 1));
+
+        clients.assign_request_to_client(client(0), 2, 0);
+        clients.complete_request_for_client(client(0), 2, None);
+        assert!(clients.is_request_abandoned_by_client(client(0), 2));
+
+        clients.assign_request_to_client(client(0), 3, 0);
+        clients.assign_request_to_client(client(1), 3, 0);
+        clients.release_request(3);
+        assert!(clients.is_request_abandoned_by_client(client(0), 3));
+        assert!(clients.is_request_abandoned_by_client(client(1), 3));
+        assert!(!clients.is_request_abandoned_by_client(client(1), 2));
+    }
+
+    #[test]
+    fn forget_the_oldest_abandoned_requests() {
+        let mut clients = connected_clients(SchedulingStrategy::LeastInFlight, 1);
+
+        for request_id in 0..=MAX_ABANDONED_REQUESTS as HttpRequestId {
+            clients.assign_request_to_client(client(0), request_id, 0);
+            clients.complete_request_for_client(client(0), request_id, None);
+        }
+
+        assert!(!clients.is_request_abandoned_by_client(client(0), 0));
+        assert!(clients.is_request_abandoned_by_client(client(0), 1));
+    }
+
+    #[test]
+    fn only_record_the_stats_of_connected_clients() {
+        let mut clients = connected_clients(SchedulingStrategy::LeastInFlight, 1);
+
+        clients.mark_client_seen(client(0), 1);
+        clients.mark_client_seen(client(1), 1);
+        clients.record_protocol_error(client(1));
+        assert!(!clients.record_client_outcome(client(1), ClientOutcome::Failed));
+
+        let health = clients.clients_health();
+        assert_eq!(health.len(), 1);
+        assert_eq!(health[0].0, client(0));
+        assert_eq!(health[0].1.last_seen_at_ms, Some(1));
+    }
+
+    #[test]
+    fn keep_the_stats_of_disconnected_clients() {
+        let mut clients = connected_clients(SchedulingStrategy::LeastInFlight, 1);
+        clients.record_protocol_error(client(0));
+        clients.remove_client(&client(0));
+
+        let health = clients.clients_health();
+        assert_eq!(health.len(), 1);
+        assert!(!health[0].1.connected);
+        assert_eq!(health[0].1.protocol_errors, 1);
+    }
+}
@@ -0,0 +1,353 @@
+use std::{fmt, io::Read};
+
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use serde::de::DeserializeOwned;
+
+use super::{HttpHeader, HttpResponse};
+
+/// The largest body that can be decompressed, to protect against decompression bombs.
+const MAX_DECODED_BODY_BYTES: u64 = 32 * 1024 * 1024;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+#[derive(Debug)]
+pub enum ContentDecodingError {
+    UnsupportedEncoding(String),
+    InvalidBody(String),
+    /// The `Content-Type` is set and is not JSON.
+    NotJson(String),
+}
+
+impl fmt::Display for ContentDecodingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContentDecodingError::UnsupportedEncoding(encoding) => {
+                write!(f, "unsupported content encoding: {}", encoding)
+            }
+            ContentDecodingError::InvalidBody(err) => write!(f, "invalid body: {}", err),
+            ContentDecodingError::NotJson(content_type) => {
+                write!(f, "expected a JSON body, got {}", content_type)
+            }
+        }
+    }
+}
+
+/// The value of the first header with the given name, compared case-insensitively.
+pub fn header_value<'a>(headers: &'a [HttpHeader], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+/// Whether the body still has to be decoded.
+///
+/// Clients may decompress the body and still forward the `Content-Encoding` header,
+/// so a gzip body without the gzip header is considered already decoded.
+/// Deflate bodies have no such header, so they are always decoded.
+fn is_encoded(encoding: &str, body: &[u8]) -> bool {
+    match encoding {
+        "gzip" | "x-gzip" => body.starts_with(&GZIP_MAGIC),
+        _ => true,
+    }
+}
+
+/// Whether the deflate body has the zlib wrapper, see RFC 1950.
+/// Some servers send the raw deflate stream instead.
+fn has_zlib_header(body: &[u8]) -> bool {
+    body.len() >= 2
+        && body[0] & 0x0f == 8
+        && (u16::from(body[0]) << 8 | u16::from(body[1])) % 31 == 0
+}
+
+fn decompress(encoding: &str, body: &[u8]) -> Result<Vec<u8>, ContentDecodingError> {
+    let reader: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(body)),
+        "deflate" if has_zlib_header(body) => Box::new(ZlibDecoder::new(body)),
+        "deflate" => Box::new(DeflateDecoder::new(body)),
+        _ => {
+            return Err(ContentDecodingError::UnsupportedEncoding(String::from(
+                encoding,
+            )))
+        }
+    };
+
+    let mut decoded = vec![];
+    reader
+        .take(MAX_DECODED_BODY_BYTES + 1)
+        .read_to_end(&mut decoded)
+        .map_err(|e| ContentDecodingError::InvalidBody(e.to_string()))?;
+
+    if decoded.len() as u64 > MAX_DECODED_BODY_BYTES {
+        return Err(ContentDecodingError::InvalidBody(format!(
+            "decoded body exceeds the limit of {} bytes",
+            MAX_DECODED_BODY_BYTES
+        )));
+    }
+
+    Ok(decoded)
+}
+
+/// Decompresses the body according to the `Content-Encoding` header,
+/// which is removed along with the `Content-Length` one.
+///
+/// Supports `gzip` and `deflate`, in any order.
+/// Fails with [ContentDecodingError::UnsupportedEncoding] for the other encodings, e.g. `br`.
+pub fn decode_http_response(response: HttpResponse) -> Result<HttpResponse, ContentDecodingError> {
+    let Some(content_encoding) = header_value(&response.headers, "content-encoding") else {
+        return Ok(response);
+    };
+
+    let encodings: Vec<String> = content_encoding
+        .split(',')
+        .map(|encoding| encoding.trim().to_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect();
+
+    let mut body = response.body;
+    // the encodings are listed in the order they were applied
+    for encoding in encodings.iter().rev() {
+        if is_encoded(encoding, &body) {
+            body = decompress(encoding, &body)?;
+        }
+    }
+
+    Ok(HttpResponse {
+        status: response.status,
+        headers: response
+            .headers
+            .into_iter()
+            .filter(|h| {
+                !h.name.eq_ignore_ascii_case("content-encoding")
+                    && !h.name.eq_ignore_ascii_case("content-length")
+            })
+            .collect(),
+        body,
+    })
+}
+
+/// Decodes the body and parses it as JSON.
+///
+/// Fails if the `Content-Type` header is set and is not `application/json` or `*/*+json`.
+pub fn decode_json_response<T: DeserializeOwned>(
+    response: HttpResponse,
+) -> Result<T, ContentDecodingError> {
+    if let Some(content_type) = header_value(&response.headers, "content-type") {
+        let mime_type = content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase();
+
+        if mime_type != "application/json" && !mime_type.ends_with("+json") {
+            return Err(ContentDecodingError::NotJson(String::from(content_type)));
+        }
+    }
+
+    let response = decode_http_response(response)?;
+
+    serde_json::from_slice(&response.body)
+        .map_err(|e| ContentDecodingError::InvalidBody(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{
+        write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+        Compression,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn header(name: &str, value: &str) -> HttpHeader {
+        HttpHeader {
+            name: String::from(name),
+            value: String::from(value),
+        }
+    }
+
+    fn response(headers: Vec<HttpHeader>, body: Vec<u8>) -> HttpResponse {
+        HttpResponse {
+            status: 200u32.into(),
+            headers,
+            body,
+        }
+    }
+
+    fn encode<W: Write>(mut encoder: W, body: &[u8]) -> W {
+        encoder.write_all(body).unwrap();
+        encoder
+    }
+
+    fn gzip(body: &[u8]) -> Vec<u8> {
+        encode(GzEncoder::new(vec![], Compression::default()), body)
+            .finish()
+            .unwrap()
+    }
+
+    fn zlib(body: &[u8]) -> Vec<u8> {
+        encode(ZlibEncoder::new(vec![], Compression::default()), body)
+            .finish()
+            .unwrap()
+    }
+
+    fn raw_deflate(body: &[u8]) -> Vec<u8> {
+        encode(DeflateEncoder::new(vec![], Compression::default()), body)
+            .finish()
+            .unwrap()
+    }
+
+    fn encoded_response(content_encoding: &str, body: Vec<u8>) -> HttpResponse {
+        response(
+            vec![
+                header("Content-Encoding", content_encoding),
+                header("Content-Length", &body.len().to_string()),
+                header("Content-Type", "application/json"),
+            ],
+            body,
+        )
+    }
+
+    const BODY: &[u8] = br#"{"status":"success","data":[1,2,3]}"#;
+
+    #[test]
+    fn decode_gzip_body() {
+        let decoded = decode_http_response(encoded_response("gzip", gzip(BODY))).unwrap();
+
+        assert_eq!(decoded.body, BODY);
+        assert_eq!(
+            decoded.headers,
+            vec![header("Content-Type", "application/json")]
+        );
+    }
+
+    #[test]
+    fn decode_zlib_and_raw_deflate_bodies() {
+        for body in [zlib(BODY), raw_deflate(BODY)] {
+            let decoded = decode_http_response(encoded_response("deflate", body)).unwrap();
+
+            assert_eq!(decoded.body, BODY);
+        }
+    }
+
+    #[test]
+    fn decode_stacked_encodings_in_reverse_order() {
+        let body = gzip(&zlib(BODY));
+
+        let decoded = decode_http_response(encoded_response("deflate, GZIP", body)).unwrap();
+
+        assert_eq!(decoded.body, BODY);
+    }
+
+    #[test]
+    fn keep_body_without_content_encoding() {
+        let res = response(vec![header("Content-Length", "3")], b"abc".to_vec());
+
+        let decoded = decode_http_response(res).unwrap();
+
+        assert_eq!(decoded.body, b"abc");
+        assert_eq!(decoded.headers, vec![header("Content-Length", "3")]);
+    }
+
+    #[test]
+    fn keep_gzip_body_already_decoded_by_client() {
+        let decoded = decode_http_response(encoded_response("gzip", BODY.to_vec())).unwrap();
+
+        assert_eq!(decoded.body, BODY);
+    }
+
+    #[test]
+    fn keep_empty_body_with_identity_encoding() {
+        let decoded = decode_http_response(encoded_response("identity", vec![])).unwrap();
+
+        assert!(decoded.body.is_empty());
+    }
+
+    #[test]
+    fn reject_unsupported_encoding() {
+        let err = decode_http_response(encoded_response("br", BODY.to_vec())).unwrap_err();
+
+        assert!(
+            matches!(err, ContentDecodingError::UnsupportedEncoding(encoding) if encoding == "br")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_deflate_body() {
+        let err = decode_http_response(encoded_response("deflate", vec![0xff; 16])).unwrap_err();
+
+        assert!(matches!(err, ContentDecodingError::InvalidBody(_)));
+    }
+
+    #[test]
+    fn reject_truncated_gzip_body() {
+        let mut body = gzip(BODY);
+        body.truncate(body.len() / 2);
+
+        let err = decode_http_response(encoded_response("gzip", body)).unwrap_err();
+
+        assert!(matches!(err, ContentDecodingError::InvalidBody(_)));
+    }
+
+    #[test]
+    fn reject_oversized_decoded_body() {
+        let mut encoder = GzEncoder::new(vec![], Compression::fast());
+        let zeros = vec![0; 1024 * 1024];
+        for _ in 0..=MAX_DECODED_BODY_BYTES / zeros.len() as u64 {
+            encoder.write_all(&zeros).unwrap();
+        }
+        let body = encoder.finish().unwrap();
+
+        let err = decode_http_response(encoded_response("gzip", body)).unwrap_err();
+
+        assert!(
+            matches!(err, ContentDecodingError::InvalidBody(e) if e.contains("exceeds the limit"))
+        );
+    }
+
+    #[test]
+    fn decode_json_body() {
+        let value: Value = decode_json_response(encoded_response("gzip", gzip(BODY))).unwrap();
+
+        assert_eq!(value, json!({ "status": "success", "data": [1, 2, 3] }));
+    }
+
+    #[test]
+    fn decode_json_body_with_json_content_types() {
+        for content_type in [
+            "application/json; charset=utf-8",
+            "application/problem+json",
+        ] {
+            let res = response(vec![header("Content-Type", content_type)], BODY.to_vec());
+
+            assert!(decode_json_response::<Value>(res).is_ok());
+        }
+
+        let res = response(vec![], BODY.to_vec());
+        assert!(decode_json_response::<Value>(res).is_ok());
+    }
+
+    #[test]
+    fn reject_non_json_content_type() {
+        let res = response(vec![header("Content-Type", "text/html")], BODY.to_vec());
+
+        let err = decode_json_response::<Value>(res).unwrap_err();
+
+        assert!(
+            matches!(err, ContentDecodingError::NotJson(content_type) if content_type == "text/html")
+        );
+    }
+
+    #[test]
+    fn reject_invalid_json_body() {
+        let res = response(vec![], vec![]);
+
+        let err = decode_json_response::<Value>(res).unwrap_err();
+
+        assert!(matches!(err, ContentDecodingError::InvalidBody(_)));
+    }
+}
@@ -0,0 +1,512 @@
+use std::{cell::RefCell, collections::BTreeMap};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+use url::Url;
+
+use crate::utils::{caller_is_controller, get_current_timestamp_ms};
+
+use super::{HttpRequestFailureReason, HttpRequestId, HttpResponse, HTTP_REQUESTS};
+
+/// A token bucket: up to `max_requests` requests can be sent in a burst,
+/// then one more every `refill_interval_ms`.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct DestinationRateLimit {
+    pub max_requests: u64,
+    pub refill_interval_ms: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct CircuitBreakerConfig {
+    /// How many consecutive failures or timeouts open the circuit.
+    /// Server errors, rate limit responses, timeouts and the hosts the clients
+    /// or the HTTPS outcalls couldn't reach count as failures,
+    /// but not the errors of the clients themselves.
+    pub failure_threshold: u32,
+    /// How long the circuit stays open before letting a probe request through.
+    pub open_duration_ms: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct DestinationPolicy {
+    /// No limit if not set.
+    pub rate_limit: Option<DestinationRateLimit>,
+    /// The circuit never opens if not set.
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct DestinationsConfig {
+    pub default_policy: DestinationPolicy,
+    /// Overrides the default policy for the given hosts.
+    pub host_policies: BTreeMap<String, DestinationPolicy>,
+}
+
+impl DestinationsConfig {
+    fn policy(&self, host: &str) -> &DestinationPolicy {
+        self.host_policies.get(host).unwrap_or(&self.default_policy)
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, PartialEq)]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    /// Requests fail right away with [HttpRequestFailureReason::CircuitOpen].
+    Open { until_ms: u64 },
+    /// A probe request has been let through, the others fail right away
+    /// until it completes or the open duration elapses again.
+    HalfOpen { probe_started_at_ms: u64 },
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct DestinationState {
+    /// The bucket is full if not set.
+    tokens: Option<u64>,
+    last_refill_at_ms: u64,
+    consecutive_failures: u32,
+    circuit: CircuitState,
+}
+
+impl DestinationState {
+    /// Takes a token from the bucket.
+    ///
+    /// Returns how long to wait for the next token if the bucket is empty.
+    fn take_token(&mut self, rate_limit: &DestinationRateLimit, now_ms: u64) -> Result<(), u64> {
+        let refill_interval_ms = rate_limit.refill_interval_ms.max(1);
+
+        let mut tokens = match self.tokens {
+            None => {
+                self.last_refill_at_ms = now_ms;
+                rate_limit.max_requests
+            }
+            Some(tokens) => {
+                let refilled = now_ms.saturating_sub(self.last_refill_at_ms) / refill_interval_ms;
+                self.last_refill_at_ms += refilled * refill_interval_ms;
+                tokens.saturating_add(refilled).min(rate_limit.max_requests)
+            }
+        };
+
+        if tokens == 0 {
+            self.tokens = Some(0);
+            return Err((self.last_refill_at_ms + refill_interval_ms).saturating_sub(now_ms));
+        }
+
+        tokens -= 1;
+        self.tokens = Some(tokens);
+        Ok(())
+    }
+
+    /// Returns the state the circuit moves to if a request is let through,
+    /// or [None] if the request must fail right away.
+    fn circuit_after_admission(
+        &self,
+        circuit_breaker: &CircuitBreakerConfig,
+        now_ms: u64,
+    ) -> Option<CircuitState> {
+        match self.circuit {
+            CircuitState::Closed => Some(CircuitState::Closed),
+            CircuitState::Open { until_ms } if now_ms < until_ms => None,
+            CircuitState::HalfOpen {
+                probe_started_at_ms,
+            } if now_ms < probe_started_at_ms + circuit_breaker.open_duration_ms => None,
+            // the probe never completed, let another one through
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                Some(CircuitState::HalfOpen {
+                    probe_started_at_ms: now_ms,
+                })
+            }
+        }
+    }
+
+    fn record_outcome(
+        &mut self,
+        is_success: bool,
+        circuit_breaker: Option<&CircuitBreakerConfig>,
+        now_ms: u64,
+    ) {
+        if is_success {
+            self.consecutive_failures = 0;
+            self.circuit = CircuitState::Closed;
+            return;
+        }
+
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        let Some(circuit_breaker) = circuit_breaker else {
+            return;
+        };
+
+        let must_open = match self.circuit {
+            CircuitState::HalfOpen { .. } => true,
+            CircuitState::Closed => self.consecutive_failures >= circuit_breaker.failure_threshold,
+            CircuitState::Open { .. } => false,
+        };
+
+        if must_open {
+            self.circuit = CircuitState::Open {
+                until_ms: now_ms + circuit_breaker.open_duration_ms,
+            };
+        }
+    }
+}
+
+pub enum DestinationAdmission {
+    Allowed,
+    /// The host's rate limit is reached, the request can be sent after the given delay.
+    Delayed(u64),
+    Rejected(HttpRequestFailureReason),
+}
+
+thread_local! {
+    /* stable */ static DESTINATIONS_CONFIG: RefCell<DestinationsConfig> = RefCell::new(DestinationsConfig::default());
+    /* flexible */ static DESTINATIONS: RefCell<BTreeMap<String, DestinationState>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn destinations_config() -> DestinationsConfig {
+    DESTINATIONS_CONFIG.with(|config| config.borrow().clone())
+}
+
+pub fn set_destinations_config(destinations_config: DestinationsConfig) {
+    DESTINATIONS_CONFIG.with(|config| *config.borrow_mut() = destinations_config);
+}
+
+/// The host the policies apply to, lowercased.
+pub fn url_host(url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+    url.host_str().map(|host| host.to_lowercase())
+}
+
+fn request_host(request_id: HttpRequestId) -> Option<String> {
+    HTTP_REQUESTS
+        .with(|http_requests| url_host(&http_requests.borrow().get(&request_id)?.request.url))
+}
+
+/// Applies the policy of the request's host before sending it.
+///
+/// Rate-limited requests are delayed, as long as they can still be sent
+/// before their pending deadline.
+pub fn admit_http_request(request_id: HttpRequestId) -> DestinationAdmission {
+    let Some(host) = request_host(request_id) else {
+        return DestinationAdmission::Allowed;
+    };
+    let policy = DESTINATIONS_CONFIG.with(|config| config.borrow().policy(&host).clone());
+    let now_ms = get_current_timestamp_ms();
+
+    let admission = DESTINATIONS.with(|destinations| {
+        let mut destinations = destinations.borrow_mut();
+        let destination = destinations.entry(host.clone()).or_default();
+
+        let circuit = match &policy.circuit_breaker {
+            Some(circuit_breaker) => {
+                match destination.circuit_after_admission(circuit_breaker, now_ms) {
+                    Some(circuit) => Some(circuit),
+                    None => {
+                        return DestinationAdmission::Rejected(
+                            HttpRequestFailureReason::CircuitOpen(host.clone()),
+                        )
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(rate_limit) = &policy.rate_limit {
+            if let Err(wait_ms) = destination.take_token(rate_limit, now_ms) {
+                return DestinationAdmission::Delayed(wait_ms);
+            }
+        }
+
+        if let Some(circuit) = circuit {
+            destination.circuit = circuit;
+        }
+
+        DestinationAdmission::Allowed
+    });
+
+    let DestinationAdmission::Delayed(wait_ms) = admission else {
+        return admission;
+    };
+
+    let pending_deadline_ms = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .map(|r| r.created_at / 1_000_000 + r.pending_deadline_ms)
+            .unwrap_or_default()
+    });
+
+    if now_ms + wait_ms > pending_deadline_ms {
+        DestinationAdmission::Rejected(HttpRequestFailureReason::RateLimited(host))
+    } else {
+        DestinationAdmission::Delayed(wait_ms)
+    }
+}
+
+/// Server errors and rate limit responses count as failures of the host.
+pub fn is_host_failure(response: &HttpResponse) -> bool {
+    response.status >= 500u32 || response.status == 429
+}
+
+/// Feeds the outcome of an attempt to the circuit breaker of the request's host.
+pub fn record_destination_outcome(request_id: HttpRequestId, is_success: bool) {
+    let Some(host) = request_host(request_id) else {
+        return;
+    };
+    let circuit_breaker =
+        DESTINATIONS_CONFIG.with(|config| config.borrow().policy(&host).circuit_breaker.clone());
+
+    DESTINATIONS.with(|destinations| {
+        destinations
+            .borrow_mut()
+            .entry(host)
+            .or_default()
+            .record_outcome(
+                is_success,
+                circuit_breaker.as_ref(),
+                get_current_timestamp_ms(),
+            )
+    });
+}
+
+#[query]
+fn get_destinations_config() -> DestinationsConfig {
+    destinations_config()
+}
+
+/// The rate limit and circuit breaker state of the hosts contacted since the last upgrade.
+#[query]
+fn get_destinations_state() -> Vec<(String, DestinationState)> {
+    DESTINATIONS.with(|destinations| {
+        destinations
+            .borrow()
+            .iter()
+            .map(|(host, state)| (host.clone(), state.clone()))
+            .collect()
+    })
+}
+
+#[update(guard = "caller_is_controller")]
+fn set_default_destination_policy(policy: DestinationPolicy) {
+    DESTINATIONS_CONFIG.with(|config| config.borrow_mut().default_policy = policy);
+}
+
+/// Sets the policy of the host, or resets it to the default one if `policy` is [None].
+#[update(guard = "caller_is_controller")]
+fn set_destination_policy(host: String, policy: Option<DestinationPolicy>) {
+    let host = host.to_lowercase();
+
+    DESTINATIONS_CONFIG.with(|config| {
+        let mut config = config.borrow_mut();
+        match policy {
+            Some(policy) => config.host_policies.insert(host, policy),
+            None => config.host_policies.remove(&host),
+        }
+    });
+}
+
+/// Closes the circuit of the host, so that requests are sent to it again right away.
+#[update(guard = "caller_is_controller")]
+fn reset_destination_circuit(host: String) {
+    DESTINATIONS.with(|destinations| {
+        if let Some(destination) = destinations.borrow_mut().get_mut(&host.to_lowercase()) {
+            destination.consecutive_failures = 0;
+            destination.circuit = CircuitState::Closed;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REFILL_INTERVAL_MS: u64 = 1_000;
+    const OPEN_DURATION_MS: u64 = 10_000;
+
+    fn rate_limit(max_requests: u64) -> DestinationRateLimit {
+        DestinationRateLimit {
+            max_requests,
+            refill_interval_ms: REFILL_INTERVAL_MS,
+        }
+    }
+
+    fn circuit_breaker(failure_threshold: u32) -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failure_threshold,
+            open_duration_ms: OPEN_DURATION_MS,
+        }
+    }
+
+    #[test]
+    fn start_with_a_full_bucket() {
+        let mut destination = DestinationState::default();
+        let rate_limit = rate_limit(3);
+
+        for _ in 0..3 {
+            assert_eq!(destination.take_token(&rate_limit, 0), Ok(()));
+        }
+        assert_eq!(
+            destination.take_token(&rate_limit, 0),
+            Err(REFILL_INTERVAL_MS)
+        );
+    }
+
+    #[test]
+    fn wait_for_the_next_token_when_empty() {
+        let mut destination = DestinationState::default();
+        let rate_limit = rate_limit(1);
+        destination.take_token(&rate_limit, 0).unwrap();
+
+        assert_eq!(destination.take_token(&rate_limit, 300), Err(700));
+        assert_eq!(
+            destination.take_token(&rate_limit, REFILL_INTERVAL_MS),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn refill_the_bucket_up_to_max_requests() {
+        let mut destination = DestinationState::default();
+        let rate_limit = rate_limit(2);
+        destination.take_token(&rate_limit, 0).unwrap();
+        destination.take_token(&rate_limit, 0).unwrap();
+
+        // enough time for 10 tokens, but the bucket holds only 2
+        let now_ms = 10 * REFILL_INTERVAL_MS;
+        assert_eq!(destination.take_token(&rate_limit, now_ms), Ok(()));
+        assert_eq!(destination.take_token(&rate_limit, now_ms), Ok(()));
+        assert_eq!(
+            destination.take_token(&rate_limit, now_ms),
+            Err(REFILL_INTERVAL_MS)
+        );
+    }
+
+    #[test]
+    fn open_the_circuit_at_the_threshold() {
+        let mut destination = DestinationState::default();
+        let circuit_breaker = circuit_breaker(3);
+
+        destination.record_outcome(false, Some(&circuit_breaker), 0);
+        destination.record_outcome(false, Some(&circuit_breaker), 0);
+        assert_eq!(destination.circuit, CircuitState::Closed);
+
+        destination.record_outcome(false, Some(&circuit_breaker), 100);
+        assert_eq!(
+            destination.circuit,
+            CircuitState::Open {
+                until_ms: 100 + OPEN_DURATION_MS
+            }
+        );
+    }
+
+    #[test]
+    fn success_resets_the_consecutive_failures() {
+        let mut destination = DestinationState::default();
+        let circuit_breaker = circuit_breaker(2);
+
+        destination.record_outcome(false, Some(&circuit_breaker), 0);
+        destination.record_outcome(true, Some(&circuit_breaker), 0);
+        destination.record_outcome(false, Some(&circuit_breaker), 0);
+
+        assert_eq!(destination.consecutive_failures, 1);
+        assert_eq!(destination.circuit, CircuitState::Closed);
+    }
+
+    #[test]
+    fn never_open_without_circuit_breaker() {
+        let mut destination = DestinationState::default();
+
+        for _ in 0..10 {
+            destination.record_outcome(false, None, 0);
+        }
+
+        assert_eq!(destination.consecutive_failures, 10);
+        assert_eq!(destination.circuit, CircuitState::Closed);
+    }
+
+    #[test]
+    fn let_a_probe_through_once_the_open_duration_elapses() {
+        let destination = DestinationState {
+            circuit: CircuitState::Open {
+                until_ms: OPEN_DURATION_MS,
+            },
+            ..Default::default()
+        };
+        let circuit_breaker = circuit_breaker(1);
+
+        assert_eq!(
+            destination.circuit_after_admission(&circuit_breaker, OPEN_DURATION_MS - 1),
+            None
+        );
+        assert_eq!(
+            destination.circuit_after_admission(&circuit_breaker, OPEN_DURATION_MS),
+            Some(CircuitState::HalfOpen {
+                probe_started_at_ms: OPEN_DURATION_MS
+            })
+        );
+    }
+
+    #[test]
+    fn reject_requests_while_the_probe_is_running() {
+        let destination = DestinationState {
+            circuit: CircuitState::HalfOpen {
+                probe_started_at_ms: 100,
+            },
+            ..Default::default()
+        };
+        let circuit_breaker = circuit_breaker(1);
+
+        assert_eq!(
+            destination.circuit_after_admission(&circuit_breaker, 100 + OPEN_DURATION_MS - 1),
+            None
+        );
+        // the probe never completed
+        assert_eq!(
+            destination.circuit_after_admission(&circuit_breaker, 100 + OPEN_DURATION_MS),
+            Some(CircuitState::HalfOpen {
+                probe_started_at_ms: 100 + OPEN_DURATION_MS
+            })
+        );
+    }
+
+    #[test]
+    fn failed_probe_opens_the_circuit_again() {
+        let mut destination = DestinationState {
+            consecutive_failures: 5,
+            circuit: CircuitState::HalfOpen {
+                probe_started_at_ms: 100,
+            },
+            ..Default::default()
+        };
+
+        destination.record_outcome(false, Some(&circuit_breaker(10)), 200);
+
+        assert_eq!(
+            destination.circuit,
+            CircuitState::Open {
+                until_ms: 200 + OPEN_DURATION_MS
+            }
+        );
+    }
+
+    #[test]
+    fn successful_probe_closes_the_circuit() {
+        let mut destination = DestinationState {
+            consecutive_failures: 5,
+            circuit: CircuitState::HalfOpen {
+                probe_started_at_ms: 100,
+            },
+            ..Default::default()
+        };
+        let circuit_breaker = circuit_breaker(1);
+
+        destination.record_outcome(true, Some(&circuit_breaker), 200);
+
+        assert_eq!(destination.circuit, CircuitState::Closed);
+        assert_eq!(destination.consecutive_failures, 0);
+        assert_eq!(
+            destination.circuit_after_admission(&circuit_breaker, 200),
+            Some(CircuitState::Closed)
+        );
+    }
+}
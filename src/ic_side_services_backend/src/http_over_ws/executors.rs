@@ -0,0 +1,236 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    time::Duration,
+};
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::{query, update};
+use ic_websocket_cdk::ClientPrincipal;
+
+use crate::{
+    logger::log,
+    utils::{caller_is_controller, get_current_timestamp_ns},
+    ws::close_client_connection,
+};
+
+use super::{dispatch_pending_http_requests, CONNECTED_CLIENTS};
+
+const EXPIRY_CHECK_INTERVAL_MS: u64 = 60_000;
+
+/// What happens to the clients that connect without being authorized.
+#[derive(CandidType, Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub enum UnauthorizedExecutorPolicy {
+    /// The connection is closed right away.
+    Reject,
+    /// The client stays connected, but doesn't receive requests until it's approved.
+    #[default]
+    Quarantine,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct AuthorizedExecutor {
+    authorized_at: u64,
+    /// The client is disconnected and can't connect anymore after this time, in nanoseconds.
+    expires_at: Option<u64>,
+}
+
+impl AuthorizedExecutor {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+    }
+}
+
+thread_local! {
+    /* stable */ static AUTHORIZED_EXECUTORS: RefCell<BTreeMap<ClientPrincipal, AuthorizedExecutor>> = const { RefCell::new(BTreeMap::new()) };
+    /* stable */ static UNAUTHORIZED_EXECUTOR_POLICY: Cell<UnauthorizedExecutorPolicy> = const { Cell::new(UnauthorizedExecutorPolicy::Quarantine) };
+    /// The connected clients waiting to be approved, with the time they connected at.
+    /* flexible */ static QUARANTINED_EXECUTORS: RefCell<BTreeMap<ClientPrincipal, u64>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub fn authorized_executors() -> BTreeMap<ClientPrincipal, AuthorizedExecutor> {
+    AUTHORIZED_EXECUTORS.with(|executors| executors.borrow().clone())
+}
+
+pub fn set_authorized_executors(
+    authorized_executors: BTreeMap<ClientPrincipal, AuthorizedExecutor>,
+) {
+    AUTHORIZED_EXECUTORS.with(|executors| *executors.borrow_mut() = authorized_executors);
+}
+
+pub fn unauthorized_executor_policy() -> UnauthorizedExecutorPolicy {
+    UNAUTHORIZED_EXECUTOR_POLICY.with(|policy| policy.get())
+}
+
+pub fn set_unauthorized_executor_policy(unauthorized_executor_policy: UnauthorizedExecutorPolicy) {
+    UNAUTHORIZED_EXECUTOR_POLICY.with(|policy| policy.set(unauthorized_executor_policy));
+}
+
+fn is_authorized(client_principal: &ClientPrincipal) -> bool {
+    let now = get_current_timestamp_ns();
+    AUTHORIZED_EXECUTORS.with(|executors| {
+        executors
+            .borrow()
+            .get(client_principal)
+            .is_some_and(|executor| !executor.is_expired(now))
+    })
+}
+
+/// Called when a client opens the connection.
+///
+/// Returns `true` if the client can receive requests,
+/// otherwise the client is rejected or quarantined according to the [UnauthorizedExecutorPolicy].
+pub fn admit_executor(client_principal: ClientPrincipal) -> bool {
+    if is_authorized(&client_principal) {
+        return true;
+    }
+
+    match unauthorized_executor_policy() {
+        UnauthorizedExecutorPolicy::Reject => {
+            log(&format!(
+                "http_over_ws: rejecting unauthorized client {}",
+                client_principal
+            ));
+            close_client_connection(client_principal);
+        }
+        UnauthorizedExecutorPolicy::Quarantine => {
+            log(&format!(
+                "http_over_ws: quarantining unauthorized client {}",
+                client_principal
+            ));
+            QUARANTINED_EXECUTORS.with(|quarantined| {
+                quarantined
+                    .borrow_mut()
+                    .insert(client_principal, get_current_timestamp_ns())
+            });
+        }
+    }
+
+    false
+}
+
+/// Called when a client closes the connection.
+pub fn release_quarantined_executor(client_principal: &ClientPrincipal) {
+    QUARANTINED_EXECUTORS.with(|quarantined| quarantined.borrow_mut().remove(client_principal));
+}
+
+pub fn start_executors_expiry_check() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_millis(EXPIRY_CHECK_INTERVAL_MS),
+        disconnect_expired_executors,
+    );
+}
+
+fn disconnect_expired_executors() {
+    let now = get_current_timestamp_ns();
+    let expired_executors: Vec<ClientPrincipal> = AUTHORIZED_EXECUTORS.with(|executors| {
+        executors
+            .borrow()
+            .iter()
+            .filter(|(_, executor)| executor.is_expired(now))
+            .map(|(client_principal, _)| *client_principal)
+            .collect()
+    });
+
+    for client_principal in expired_executors {
+        let is_connected = CONNECTED_CLIENTS.with(|clients| {
+            clients
+                .borrow()
+                .client_principals()
+                .contains(&client_principal)
+        });
+
+        if is_connected {
+            log(&format!(
+                "http_over_ws: authorization of client {} expired",
+                client_principal
+            ));
+            close_client_connection(client_principal);
+        }
+    }
+}
+
+#[query]
+fn get_authorized_executors() -> Vec<(ClientPrincipal, AuthorizedExecutor)> {
+    AUTHORIZED_EXECUTORS.with(|executors| {
+        executors
+            .borrow()
+            .iter()
+            .map(|(client_principal, executor)| (*client_principal, executor.clone()))
+            .collect()
+    })
+}
+
+#[query]
+fn get_quarantined_executors() -> Vec<(ClientPrincipal, u64)> {
+    QUARANTINED_EXECUTORS.with(|quarantined| {
+        quarantined
+            .borrow()
+            .iter()
+            .map(|(client_principal, connected_at)| (*client_principal, *connected_at))
+            .collect()
+    })
+}
+
+#[query]
+fn get_unauthorized_executor_policy() -> UnauthorizedExecutorPolicy {
+    unauthorized_executor_policy()
+}
+
+#[update(
+    name = "set_unauthorized_executor_policy",
+    guard = "caller_is_controller"
+)]
+fn set_unauthorized_executor_policy_endpoint(policy: UnauthorizedExecutorPolicy) {
+    set_unauthorized_executor_policy(policy);
+}
+
+/// Authorizes the client, or updates its expiry.
+///
+/// If the client is quarantined, it starts receiving requests right away.
+#[update(guard = "caller_is_controller")]
+fn approve_executor(client_principal: ClientPrincipal, expires_at: Option<u64>) {
+    AUTHORIZED_EXECUTORS.with(|executors| {
+        executors.borrow_mut().insert(
+            client_principal,
+            AuthorizedExecutor {
+                authorized_at: get_current_timestamp_ns(),
+                expires_at,
+            },
+        )
+    });
+
+    if !is_authorized(&client_principal) {
+        return;
+    }
+
+    let was_quarantined = QUARANTINED_EXECUTORS
+        .with(|quarantined| quarantined.borrow_mut().remove(&client_principal))
+        .is_some();
+
+    if was_quarantined {
+        CONNECTED_CLIENTS.with(|clients| clients.borrow_mut().add_client(client_principal));
+
+        dispatch_pending_http_requests();
+    }
+}
+
+/// Removes the client's authorization and closes its connection, if any.
+/// The requests it had in flight are reassigned or failed, as if it disconnected.
+#[update(guard = "caller_is_controller")]
+fn revoke_executor(client_principal: ClientPrincipal) {
+    AUTHORIZED_EXECUTORS.with(|executors| executors.borrow_mut().remove(&client_principal));
+
+    let is_connected = CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow()
+            .client_principals()
+            .contains(&client_principal)
+    });
+    let is_quarantined = QUARANTINED_EXECUTORS
+        .with(|quarantined| quarantined.borrow().contains_key(&client_principal));
+
+    if is_connected || is_quarantined {
+        close_client_connection(client_principal);
+    }
+}
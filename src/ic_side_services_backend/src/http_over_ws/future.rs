@@ -0,0 +1,66 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+
+use super::{HttpRequestFailureReason, HttpRequestId, HttpRequestResult, HTTP_REQUESTS};
+
+thread_local! {
+    /// The tasks waiting for a request to complete.
+    /* flexible */ static HTTP_REQUEST_WAKERS: RefCell<BTreeMap<HttpRequestId, Vec<Waker>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+/// Resolves with the final outcome of the request,
+/// that is after the retries, the quorum and the transform.
+///
+/// Unlike callbacks, pending futures don't survive upgrades.
+pub struct HttpRequestFuture {
+    request_id: HttpRequestId,
+}
+
+impl Future for HttpRequestFuture {
+    type Output = HttpRequestResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let result = HTTP_REQUESTS.with(|http_requests| {
+            match http_requests.borrow().get(&self.request_id) {
+                Some(r) => r.result(),
+                None => Some(Err(HttpRequestFailureReason::NotFound)),
+            }
+        });
+
+        match result {
+            Some(result) => Poll::Ready(result),
+            None => {
+                HTTP_REQUEST_WAKERS.with(|wakers| {
+                    let mut wakers = wakers.borrow_mut();
+                    let wakers = wakers.entry(self.request_id).or_default();
+                    if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+                        wakers.push(cx.waker().clone());
+                    }
+                });
+
+                Poll::Pending
+            }
+        }
+    }
+}
+
+pub fn wait_for_http_request(request_id: HttpRequestId) -> HttpRequestFuture {
+    HttpRequestFuture { request_id }
+}
+
+/// Must be called once the request is completed and the state is no longer borrowed,
+/// since waking polls the waiting tasks right away.
+pub fn wake_http_request_waiters(request_id: HttpRequestId) {
+    let wakers = HTTP_REQUEST_WAKERS
+        .with(|wakers| wakers.borrow_mut().remove(&request_id))
+        .unwrap_or_default();
+
+    for waker in wakers {
+        waker.wake();
+    }
+}
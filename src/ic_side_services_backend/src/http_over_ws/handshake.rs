@@ -0,0 +1,89 @@
+use candid::{CandidType, Deserialize};
+use ic_websocket_cdk::ClientPrincipal;
+
+use crate::ws::send_ws_message;
+
+use super::{chunks::MAX_CHUNK_BYTES, HttpMethod, HttpOverWsMessage, HttpRequest};
+
+/// The version of the [HttpOverWsMessage] protocol spoken by the canister.
+///
+/// - 1: requests, responses and errors, without handshake.
+/// - 2: the handshake and the chunked bodies.
+/// - 3: the cancellation of requests.
+/// - 4: the errors of the hosts, reported apart from the client errors.
+pub const PROTOCOL_VERSION: u32 = 4;
+/// Clients speaking an older version are disconnected.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Sent by the canister in response to the [ExecutorCapabilities],
+/// so that clients speaking the version 1, which don't send them, never receive it.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct CanisterHello {
+    pub protocol_version: u32,
+    pub min_protocol_version: u32,
+}
+
+/// Sent by the client right after opening the connection.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ExecutorCapabilities {
+    pub protocol_version: u32,
+    pub supported_methods: Vec<HttpMethod>,
+    /// The largest request body the client accepts. No limit if not set.
+    pub max_body_bytes: Option<u64>,
+    /// The content encodings the client can decompress, e.g. `gzip`.
+    pub supported_encodings: Vec<String>,
+    pub executor_version: String,
+}
+
+impl ExecutorCapabilities {
+    /// Assumed for the clients that don't send their capabilities,
+    /// which speak the protocol version 1.
+    pub fn legacy() -> Self {
+        Self {
+            protocol_version: 1,
+            supported_methods: vec![
+                HttpMethod::GET,
+                HttpMethod::POST,
+                HttpMethod::PUT,
+                HttpMethod::HEAD,
+                HttpMethod::DELETE,
+            ],
+            max_body_bytes: Some(MAX_CHUNK_BYTES as u64),
+            supported_encodings: vec![],
+            executor_version: String::from("unknown"),
+        }
+    }
+
+    pub fn is_protocol_supported(&self) -> bool {
+        self.protocol_version >= MIN_PROTOCOL_VERSION
+    }
+
+    pub fn supports_cancellation(&self) -> bool {
+        self.protocol_version >= 3
+    }
+
+    /// Whether the client can execute the request.
+    pub fn supports(&self, http_request: &HttpRequest) -> bool {
+        let body_bytes = http_request.body.as_ref().map_or(0, |body| body.len());
+
+        // chunked bodies are only understood from version 2
+        if self.protocol_version < 2 && body_bytes > MAX_CHUNK_BYTES {
+            return false;
+        }
+
+        self.supported_methods.contains(&http_request.method)
+            && self
+                .max_body_bytes
+                .is_none_or(|max_body_bytes| body_bytes as u64 <= max_body_bytes)
+    }
+}
+
+pub fn send_hello(client_principal: ClientPrincipal) {
+    send_ws_message(
+        client_principal,
+        HttpOverWsMessage::Hello(CanisterHello {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        }),
+    );
+}
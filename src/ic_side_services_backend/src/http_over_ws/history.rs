@@ -0,0 +1,416 @@
+use std::{collections::BTreeMap, mem::discriminant, ops::Bound};
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::{caller, query};
+use ic_websocket_cdk::ClientPrincipal;
+
+use super::{
+    destinations::url_host, lifecycle::HttpRequestStatus, HttpMethod, HttpRequestFailureReason,
+    HttpRequestId, HttpRequestState, HTTP_REQUESTS,
+};
+
+/// How many requests are returned if the limit is not specified.
+const DEFAULT_LIST_LIMIT: u32 = 100;
+const MAX_LIST_LIMIT: u32 = 1_000;
+
+/// All the criteria that are set must match.
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct HttpRequestsFilter {
+    pub status: Option<HttpRequestStatus>,
+    /// Compared case-insensitively with the host of the request's URL.
+    pub host: Option<String>,
+    pub method: Option<HttpMethod>,
+    /// Matches the requests that have been attempted by the client.
+    pub client: Option<ClientPrincipal>,
+    /// In nanoseconds, inclusive.
+    pub created_after: Option<u64>,
+    /// In nanoseconds, exclusive.
+    pub created_before: Option<u64>,
+    /// Only the variant is compared, its details are ignored.
+    pub failure_reason: Option<HttpRequestFailureReason>,
+}
+
+impl HttpRequestsFilter {
+    fn matches(&self, r: &HttpRequestState) -> bool {
+        self.status.is_none_or(|status| r.status == status)
+            && self.host.as_ref().is_none_or(|host| {
+                url_host(&r.request.url).is_some_and(|h| h == host.to_lowercase())
+            })
+            && self
+                .method
+                .as_ref()
+                .is_none_or(|method| r.request.method == *method)
+            && self
+                .client
+                .is_none_or(|client| r.attempted_clients().contains(&client))
+            && self
+                .created_after
+                .is_none_or(|created_after| r.created_at >= created_after)
+            && self
+                .created_before
+                .is_none_or(|created_before| r.created_at < created_before)
+            && self.failure_reason.as_ref().is_none_or(|failure_reason| {
+                r.failure_reason
+                    .as_ref()
+                    .is_some_and(|reason| discriminant(reason) == discriminant(failure_reason))
+            })
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct ListHttpRequestsArgs {
+    pub filter: HttpRequestsFilter,
+    /// The `next_cursor` of the previous page, [None] for the first page.
+    pub cursor: Option<HttpRequestId>,
+    /// Capped at [MAX_LIST_LIMIT].
+    pub limit: Option<u32>,
+}
+
+/// A compact view of a request, without headers and bodies.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequestListEntry {
+    pub id: HttpRequestId,
+    pub url: String,
+    pub method: HttpMethod,
+    pub status: HttpRequestStatus,
+    /// The status code of the response, if any.
+    pub status_code: Option<Nat>,
+    pub failure_reason: Option<HttpRequestFailureReason>,
+    /// The clients the request has been sent to, in order.
+    pub clients: Vec<ClientPrincipal>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+    /// From the creation to the completion of the request.
+    pub latency_ms: Option<u64>,
+    pub request_body_bytes: u64,
+    pub response_body_bytes: Option<u64>,
+}
+
+impl HttpRequestListEntry {
+    fn new(id: HttpRequestId, r: &HttpRequestState) -> Self {
+        HttpRequestListEntry {
+            id,
+            url: r.request.url.clone(),
+            method: r.request.method.clone(),
+            status: r.status,
+            status_code: r.response.as_ref().map(|res| res.status.clone()),
+            failure_reason: r.failure_reason.clone(),
+            clients: r.attempted_clients(),
+            created_at: r.created_at,
+            completed_at: r.completed_at,
+            latency_ms: r
+                .completed_at
+                .map(|completed_at| completed_at.saturating_sub(r.created_at) / 1_000_000),
+            request_body_bytes: r.request.body.as_ref().map_or(0, |body| body.len() as u64),
+            response_body_bytes: r.response.as_ref().map(|res| res.body.len() as u64),
+        }
+    }
+}
+
+#[derive(CandidType, Debug, Deserialize)]
+pub struct ListHttpRequestsResult {
+    pub requests: Vec<HttpRequestListEntry>,
+    /// Set if there are more requests to list.
+    pub next_cursor: Option<HttpRequestId>,
+}
+
+/// Lists the readable requests that match the filter, from the newest to the oldest.
+fn list_matching_http_requests(
+    http_requests: &BTreeMap<HttpRequestId, HttpRequestState>,
+    args: &ListHttpRequestsArgs,
+    is_readable: impl Fn(&HttpRequestState) -> bool,
+) -> ListHttpRequestsResult {
+    let limit = args
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT) as usize;
+
+    let mut matching = http_requests
+        .range((
+            Bound::Unbounded,
+            args.cursor.map_or(Bound::Unbounded, Bound::Excluded),
+        ))
+        .rev()
+        .filter(|(_, r)| is_readable(r) && args.filter.matches(r));
+
+    let requests: Vec<HttpRequestListEntry> = matching
+        .by_ref()
+        .take(limit)
+        .map(|(id, r)| HttpRequestListEntry::new(*id, r))
+        .collect();
+
+    let next_cursor = match requests.last() {
+        Some(last) if matching.next().is_some() => Some(last.id),
+        _ => None,
+    };
+
+    ListHttpRequestsResult {
+        requests,
+        next_cursor,
+    }
+}
+
+/// Lists the requests readable by the caller, from the newest to the oldest.
+#[query]
+fn list_http_requests(args: ListHttpRequestsArgs) -> ListHttpRequestsResult {
+    let caller = caller();
+
+    HTTP_REQUESTS.with(|http_requests| {
+        list_matching_http_requests(&http_requests.borrow(), &args, |r| {
+            r.is_readable_by(&caller)
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+    use crate::http_over_ws::{HttpRequest, HttpRequestAttempt, HttpRequestExecutor};
+
+    fn client(id: u8) -> ClientPrincipal {
+        Principal::from_slice(&[id])
+    }
+
+    fn http_request(url: &str, method: HttpMethod, created_at: u64) -> HttpRequestState {
+        HttpRequestState {
+            request: HttpRequest {
+                url: String::from(url),
+                method,
+                headers: vec![],
+                body: None,
+            },
+            response: None,
+            callback: None,
+            timeout_ms: None,
+            retry_policy: Default::default(),
+            pending_deadline_ms: 0,
+            https_outcall_policy: None,
+            quorum: None,
+            transform: None,
+            requester: None,
+            sensitive: Default::default(),
+            attempts: vec![],
+            created_at,
+            completed_at: None,
+            timer_id: None,
+            failure_reason: None,
+            status: HttpRequestStatus::Queued,
+            timeline: vec![],
+            deadline_ms: 0,
+            deadline_timer_id: None,
+        }
+    }
+
+    fn failed_http_request(
+        client_principal: ClientPrincipal,
+        failure_reason: HttpRequestFailureReason,
+    ) -> HttpRequestState {
+        let mut r = http_request("https://example.com/", HttpMethod::GET, 0);
+        r.attempts.push(HttpRequestAttempt {
+            executor: HttpRequestExecutor::Client(client_principal),
+            started_at: 0,
+            ended_at: Some(1),
+            failure_reason: Some(failure_reason.clone()),
+        });
+        r.completed_at = Some(1);
+        r.status = HttpRequestStatus::Failed;
+        r.failure_reason = Some(failure_reason);
+        r
+    }
+
+    fn http_requests(count: u64) -> BTreeMap<HttpRequestId, HttpRequestState> {
+        (0..count)
+            .map(|id| {
+                (
+                    id as HttpRequestId,
+                    http_request("https://example.com/", HttpMethod::GET, id),
+                )
+            })
+            .collect()
+    }
+
+    fn list(
+        http_requests: &BTreeMap<HttpRequestId, HttpRequestState>,
+        filter: HttpRequestsFilter,
+        cursor: Option<HttpRequestId>,
+        limit: u32,
+    ) -> (Vec<HttpRequestId>, Option<HttpRequestId>) {
+        let args = ListHttpRequestsArgs {
+            filter,
+            cursor,
+            limit: Some(limit),
+        };
+        let result = list_matching_http_requests(http_requests, &args, |_| true);
+
+        (
+            result.requests.iter().map(|r| r.id).collect(),
+            result.next_cursor,
+        )
+    }
+
+    #[test]
+    fn empty_filter_matches_all() {
+        let r = http_request("https://example.com/", HttpMethod::GET, 0);
+
+        assert!(HttpRequestsFilter::default().matches(&r));
+    }
+
+    #[test]
+    fn filter_by_host_case_insensitively() {
+        let r = http_request("https://API.Example.com/path", HttpMethod::GET, 0);
+
+        let filter = |host: &str| HttpRequestsFilter {
+            host: Some(String::from(host)),
+            ..Default::default()
+        };
+
+        assert!(filter("api.example.com").matches(&r));
+        assert!(filter("API.EXAMPLE.COM").matches(&r));
+        assert!(!filter("example.com").matches(&r));
+    }
+
+    #[test]
+    fn filter_by_method_and_status() {
+        let r = http_request("https://example.com/", HttpMethod::POST, 0);
+
+        let post = HttpRequestsFilter {
+            method: Some(HttpMethod::POST),
+            status: Some(HttpRequestStatus::Queued),
+            ..Default::default()
+        };
+        let get = HttpRequestsFilter {
+            method: Some(HttpMethod::GET),
+            ..Default::default()
+        };
+        let failed = HttpRequestsFilter {
+            status: Some(HttpRequestStatus::Failed),
+            ..Default::default()
+        };
+
+        assert!(post.matches(&r));
+        assert!(!get.matches(&r));
+        assert!(!failed.matches(&r));
+    }
+
+    #[test]
+    fn filter_by_creation_time() {
+        let r = http_request("https://example.com/", HttpMethod::GET, 10);
+
+        let filter = |created_after: Option<u64>, created_before: Option<u64>| HttpRequestsFilter {
+            created_after,
+            created_before,
+            ..Default::default()
+        };
+
+        assert!(filter(Some(10), Some(11)).matches(&r));
+        assert!(!filter(Some(11), None).matches(&r));
+        assert!(!filter(None, Some(10)).matches(&r));
+    }
+
+    #[test]
+    fn filter_by_client() {
+        let r = failed_http_request(client(1), HttpRequestFailureReason::Timeout);
+
+        let filter = |client_principal| HttpRequestsFilter {
+            client: Some(client_principal),
+            ..Default::default()
+        };
+
+        assert!(filter(client(1)).matches(&r));
+        assert!(!filter(client(2)).matches(&r));
+    }
+
+    #[test]
+    fn filter_by_failure_reason_variant() {
+        let r = failed_http_request(
+            client(1),
+            HttpRequestFailureReason::ErrorFromClient(String::from("connection reset")),
+        );
+
+        let filter = |failure_reason| HttpRequestsFilter {
+            failure_reason: Some(failure_reason),
+            ..Default::default()
+        };
+
+        assert!(filter(HttpRequestFailureReason::ErrorFromClient(String::new())).matches(&r));
+        assert!(!filter(HttpRequestFailureReason::Timeout).matches(&r));
+        assert!(
+            !filter(HttpRequestFailureReason::Timeout).matches(&http_request(
+                "https://example.com/",
+                HttpMethod::GET,
+                0
+            ))
+        );
+    }
+
+    #[test]
+    fn paginate_from_newest_to_oldest() {
+        let http_requests = http_requests(5);
+
+        let (page, cursor) = list(&http_requests, Default::default(), None, 2);
+        assert_eq!(page, vec![4, 3]);
+        assert_eq!(cursor, Some(3));
+
+        let (page, cursor) = list(&http_requests, Default::default(), cursor, 2);
+        assert_eq!(page, vec![2, 1]);
+        assert_eq!(cursor, Some(1));
+
+        let (page, cursor) = list(&http_requests, Default::default(), cursor, 2);
+        assert_eq!(page, vec![0]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn no_cursor_when_last_page_is_full() {
+        let http_requests = http_requests(4);
+
+        let (page, cursor) = list(&http_requests, Default::default(), Some(2), 2);
+
+        assert_eq!(page, vec![1, 0]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn empty_page_past_the_oldest_request() {
+        let http_requests = http_requests(3);
+
+        let (page, cursor) = list(&http_requests, Default::default(), Some(0), 2);
+
+        assert!(page.is_empty());
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn paginate_only_matching_and_readable_requests() {
+        let mut http_requests = http_requests(6);
+        http_requests.get_mut(&4).unwrap().request.method = HttpMethod::POST;
+        http_requests.get_mut(&1).unwrap().request.method = HttpMethod::POST;
+        http_requests.get_mut(&1).unwrap().requester = Some(client(1));
+
+        let args = ListHttpRequestsArgs {
+            filter: HttpRequestsFilter {
+                method: Some(HttpMethod::GET),
+                ..Default::default()
+            },
+            cursor: None,
+            limit: Some(3),
+        };
+        let result = list_matching_http_requests(&http_requests, &args, |r| r.requester.is_none());
+
+        let ids: Vec<_> = result.requests.iter().map(|r| r.id).collect();
+        assert_eq!(ids, vec![5, 3, 2]);
+        assert_eq!(result.next_cursor, Some(2));
+    }
+
+    #[test]
+    fn limit_is_at_least_one() {
+        let http_requests = http_requests(2);
+
+        let (page, cursor) = list(&http_requests, Default::default(), None, 0);
+
+        assert_eq!(page, vec![1]);
+        assert_eq!(cursor, Some(1));
+    }
+}
@@ -0,0 +1,208 @@
+use std::cell::Cell;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::{
+    api::{
+        call::msg_cycles_refunded128,
+        management_canister::http_request::{
+            http_request, CanisterHttpRequestArgument, HttpMethod as ApiHttpMethod, TransformArgs,
+            TransformContext,
+        },
+    },
+    query, update,
+};
+
+use crate::{logger::log, utils::caller_is_controller};
+
+use super::{HttpMethod, HttpRequest, HttpResponse};
+
+/// The size of the subnet the canister is deployed on.
+///
+/// See https://internetcomputer.org/docs/current/developer-docs/gas-cost#special-features.
+const SUBNET_SIZE: u128 = 13;
+/// The maximum allowed by the management canister.
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+const TRANSFORM_FUNCTION_NAME: &str = "transform_https_outcall_response";
+
+/// Defines if the request can be executed through the management canister's HTTPS outcalls,
+/// instead of a WebSocket client.
+///
+/// Keep in mind that every replica of the subnet executes the request,
+/// so it should be idempotent and its response deterministic.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpsOutcallPolicy {
+    /// Skip the WebSocket clients and always use HTTPS outcalls.
+    /// Otherwise, HTTPS outcalls are used only when no client is available.
+    pub always: bool,
+    /// Defaults to [DEFAULT_MAX_RESPONSE_BYTES]. The lower, the cheaper.
+    pub max_response_bytes: Option<u64>,
+    /// If set, the HTTPS outcall is paid from these cycles, reserved for the request,
+    /// instead of the shared budget. The unspent ones are refunded to the requester.
+    pub cycles: Option<u128>,
+}
+
+thread_local! {
+    /// The cycles that can still be spent on HTTPS outcalls.
+    /* stable */ static HTTPS_OUTCALLS_CYCLES_BUDGET: Cell<u128> = const { Cell::new(0) };
+}
+
+/// See https://internetcomputer.org/docs/current/developer-docs/gas-cost#special-features.
+fn https_outcall_cost(arg: &CanisterHttpRequestArgument) -> u128 {
+    let request_bytes = arg.url.len()
+        + arg
+            .headers
+            .iter()
+            .map(|h| h.name.len() + h.value.len())
+            .sum::<usize>()
+        + arg.body.as_ref().map(|b| b.len()).unwrap_or_default()
+        + TRANSFORM_FUNCTION_NAME.len();
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+
+    (3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE
+        + 400 * SUBNET_SIZE * request_bytes as u128
+        + 800 * SUBNET_SIZE * max_response_bytes as u128
+}
+
+/// Returns [None] if the method is not supported by HTTPS outcalls.
+fn to_api_method(method: &HttpMethod) -> Option<ApiHttpMethod> {
+    match method {
+        HttpMethod::GET => Some(ApiHttpMethod::GET),
+        HttpMethod::POST => Some(ApiHttpMethod::POST),
+        HttpMethod::HEAD => Some(ApiHttpMethod::HEAD),
+        HttpMethod::PUT | HttpMethod::DELETE => None,
+    }
+}
+
+pub fn https_outcalls_cycles_budget() -> u128 {
+    HTTPS_OUTCALLS_CYCLES_BUDGET.with(|budget| budget.get())
+}
+
+pub fn set_https_outcalls_cycles_budget(cycles: u128) {
+    HTTPS_OUTCALLS_CYCLES_BUDGET.with(|budget| budget.set(cycles));
+}
+
+pub fn add_https_outcalls_cycles(cycles: u128) {
+    HTTPS_OUTCALLS_CYCLES_BUDGET.with(|budget| budget.set(budget.get().saturating_add(cycles)));
+}
+
+/// Returns [None] if the method is not supported by HTTPS outcalls.
+fn to_https_outcall_arg(
+    request: HttpRequest,
+    policy: &HttpsOutcallPolicy,
+) -> Option<CanisterHttpRequestArgument> {
+    Some(CanisterHttpRequestArgument {
+        method: to_api_method(&request.method)?,
+        url: request.url,
+        max_response_bytes: Some(
+            policy
+                .max_response_bytes
+                .unwrap_or(DEFAULT_MAX_RESPONSE_BYTES),
+        ),
+        headers: request.headers,
+        body: request.body,
+        transform: Some(TransformContext::from_name(
+            TRANSFORM_FUNCTION_NAME.to_string(),
+            vec![],
+        )),
+    })
+}
+
+/// The cost of executing the request through an HTTPS outcall,
+/// or [None] if its method is not supported.
+pub fn cost(request: &HttpRequest, policy: &HttpsOutcallPolicy) -> Option<u128> {
+    to_https_outcall_arg(request.clone(), policy).map(|arg| https_outcall_cost(&arg))
+}
+
+/// Whether the request can be executed through an HTTPS outcall, that is if its method
+/// is supported and its reserved cycles, or the budget if none, cover its cost.
+pub fn can_execute(request: &HttpRequest, policy: &HttpsOutcallPolicy) -> bool {
+    cost(request, policy)
+        .is_some_and(|cost| cost <= policy.cycles.unwrap_or_else(https_outcalls_cycles_budget))
+}
+
+/// Takes the cycles from the HTTPS outcalls cycles budget.
+fn spend_budget(cycles: u128) -> Result<(), String> {
+    HTTPS_OUTCALLS_CYCLES_BUDGET.with(|budget| {
+        let remaining = budget.get();
+        if remaining < cycles {
+            return Err(format!(
+                "HTTPS outcall costs {} cycles, but only {} are left in the budget",
+                cycles, remaining
+            ));
+        }
+        budget.set(remaining - cycles);
+        Ok(())
+    })
+}
+
+/// Executes the request through the management canister, paying for it
+/// from the cycles reserved by the policy or from the HTTPS outcalls cycles budget.
+///
+/// Returns the outcome along with the cycles spent.
+pub async fn execute_https_outcall(
+    request: HttpRequest,
+    policy: HttpsOutcallPolicy,
+) -> (Result<HttpResponse, String>, u128) {
+    let method = request.method.clone();
+    let Some(arg) = to_https_outcall_arg(request, &policy) else {
+        return (
+            Err(format!("{:?} is not supported by HTTPS outcalls", method)),
+            0,
+        );
+    };
+
+    let cycles = https_outcall_cost(&arg);
+
+    let funded = match policy.cycles {
+        Some(reserved) if reserved < cycles => Err(format!(
+            "HTTPS outcall costs {} cycles, but only {} are reserved for the request",
+            cycles, reserved
+        )),
+        Some(_) => Ok(()),
+        None => spend_budget(cycles),
+    };
+    if let Err(err) = funded {
+        return (Err(err), 0);
+    }
+
+    let result = http_request(arg, cycles).await;
+
+    let refunded = msg_cycles_refunded128();
+    if policy.cycles.is_none() {
+        add_https_outcalls_cycles(refunded);
+    }
+
+    let result = result.map(|(response,)| response).map_err(|(code, msg)| {
+        log(&format!(
+            "https_outcall: failed with code {:?}: {}",
+            code, msg
+        ));
+        msg
+    });
+
+    (result, cycles.saturating_sub(refunded))
+}
+
+/// Drops the headers, which usually differ across replicas
+/// (e.g. `date`), so that the subnet can reach consensus on the response.
+#[query]
+fn transform_https_outcall_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+#[query]
+fn get_https_outcalls_cycles_budget() -> u128 {
+    https_outcalls_cycles_budget()
+}
+
+#[update(
+    name = "set_https_outcalls_cycles_budget",
+    guard = "caller_is_controller"
+)]
+fn set_https_outcalls_cycles_budget_endpoint(cycles: u128) {
+    set_https_outcalls_cycles_budget(cycles);
+}
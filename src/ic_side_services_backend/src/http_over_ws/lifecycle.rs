@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use candid::{CandidType, Deserialize};
+use ic_cdk::{caller, query};
+
+use crate::utils::{get_current_timestamp_ms, get_current_timestamp_ns};
+
+use super::{
+    cancel::abort_http_request, HttpRequestFailureReason, HttpRequestId, HttpRequestResult,
+    HttpRequestState, HTTP_REQUESTS,
+};
+
+/// How long a request can take overall, if not specified otherwise.
+pub const DEFAULT_DEADLINE_MS: u64 = 5 * 60_000;
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum HttpRequestStatus {
+    /// Waiting to be sent, for a client to become available or for the next retry.
+    Queued,
+    /// Sent to one or more clients, or to an HTTPS outcall.
+    Assigned,
+    /// A response has been received and is being processed.
+    Responded,
+    /// The outcome is known and the callback is running.
+    /// The request stays in this state if the callback traps.
+    CallbackRunning,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl HttpRequestStatus {
+    fn from_result(result: &HttpRequestResult) -> Self {
+        match result {
+            Ok(_) => HttpRequestStatus::Completed,
+            Err(HttpRequestFailureReason::Cancelled) => HttpRequestStatus::Cancelled,
+            Err(_) => HttpRequestStatus::Failed,
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequestTransition {
+    pub status: HttpRequestStatus,
+    pub at: u64,
+    /// E.g. the client the request was assigned to, or the failure reason.
+    pub detail: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct HttpRequestTimeline {
+    status: HttpRequestStatus,
+    created_at: u64,
+    deadline_at: u64,
+    transitions: Vec<HttpRequestTransition>,
+}
+
+impl HttpRequestState {
+    pub(super) fn transition(&mut self, status: HttpRequestStatus, detail: Option<String>) {
+        if status == self.status && detail.is_none() {
+            return;
+        }
+
+        self.status = status;
+        self.timeline.push(HttpRequestTransition {
+            status,
+            at: get_current_timestamp_ns(),
+            detail,
+        });
+    }
+
+    /// Moves the request to its final status, according to its outcome.
+    pub(super) fn finish(&mut self) {
+        let Some(result) = self.result() else {
+            return;
+        };
+
+        let detail = result.as_ref().err().map(|err| format!("{:?}", err));
+        self.transition(HttpRequestStatus::from_result(&result), detail);
+    }
+
+    /// In milliseconds.
+    fn deadline_at(&self) -> u64 {
+        self.created_at / 1_000_000 + self.deadline_ms
+    }
+
+    pub(super) fn clear_deadline_timer(&mut self) {
+        if let Some(timer_id) = self.deadline_timer_id.take() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
+    }
+}
+
+pub fn set_http_request_status(
+    request_id: HttpRequestId,
+    status: HttpRequestStatus,
+    detail: Option<String>,
+) {
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.transition(status, detail);
+        }
+    });
+}
+
+/// Called once the request is completed and its callback, if any, has returned.
+pub fn finish_http_request(request_id: HttpRequestId) {
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.finish();
+        }
+    });
+}
+
+/// Fails the request with [HttpRequestFailureReason::Timeout]
+/// if it's not completed by its deadline.
+pub fn start_deadline_timer(request_id: HttpRequestId) {
+    let Some(deadline_at) = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .map(|r| r.deadline_at())
+    }) else {
+        return;
+    };
+
+    let delay_ms = deadline_at.saturating_sub(get_current_timestamp_ms());
+    let timer_id = ic_cdk_timers::set_timer(Duration::from_millis(delay_ms), move || {
+        http_request_deadline_expired(request_id)
+    });
+
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.clear_deadline_timer();
+            r.deadline_timer_id = Some(timer_id);
+        }
+    });
+}
+
+fn http_request_deadline_expired(request_id: HttpRequestId) {
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.deadline_timer_id = None;
+        }
+    });
+
+    // fails if the request is already completed
+    let _ = abort_http_request(request_id, HttpRequestFailureReason::Timeout);
+}
+
+#[query]
+fn get_http_request_timeline(request_id: HttpRequestId) -> Option<HttpRequestTimeline> {
+    HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .filter(|r| r.is_readable_by(&caller()))
+            .map(|r| HttpRequestTimeline {
+                status: r.status,
+                created_at: r.created_at,
+                deadline_at: r.deadline_at() * 1_000_000,
+                transitions: r.timeline.clone(),
+            })
+    })
+}
@@ -0,0 +1,315 @@
+use candid::{decode_one, encode_one, CandidType, Deserialize, Principal};
+use ic_cdk::{
+    api::{
+        call::{
+            call_raw128, msg_cycles_accept128, msg_cycles_available128, CallResult, RejectionCode,
+        },
+        management_canister::http_request::{
+            CanisterHttpRequestArgument, HttpMethod as ApiHttpMethod, TransformArgs,
+            TransformContext,
+        },
+    },
+    caller, id, update,
+};
+use url::Url;
+
+use crate::logger::log;
+
+use super::{
+    access::{authorize_caller, ExecuteHttpRequestError},
+    callback::call_http_callback,
+    execute_raw_http_request, https_outcall,
+    proxy::notify_requester_callback,
+    register_http_callback, wait_for_http_request, HttpCallbackArgs, HttpCallbackContext,
+    HttpMethod, HttpRequest, HttpRequestFailureReason, HttpRequestId, HttpRequestOptions,
+    HttpRequestResult, HttpResponse, HttpsOutcallPolicy,
+};
+
+const MANAGEMENT_RESPONSE_CALLBACK_NAME: &str = "http_over_ws_management_response";
+
+/// The maximum allowed by the management canister.
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+/// Roughly how long the management canister waits for a response.
+const DEFAULT_TIMEOUT_MS: u64 = 30_000;
+/// The largest reply accepted from a transform function,
+/// the candid encoding of a response of [DEFAULT_MAX_RESPONSE_BYTES] with some room.
+const MAX_TRANSFORM_REPLY_BYTES: usize = DEFAULT_MAX_RESPONSE_BYTES as usize + 64 * 1024;
+
+fn from_api_method(method: &ApiHttpMethod) -> HttpMethod {
+    match method {
+        ApiHttpMethod::GET => HttpMethod::GET,
+        ApiHttpMethod::POST => HttpMethod::POST,
+        ApiHttpMethod::HEAD => HttpMethod::HEAD,
+    }
+}
+
+/// Maps the failure to the rejection the management canister would have returned.
+fn to_rejection(failure_reason: HttpRequestFailureReason) -> (RejectionCode, String) {
+    let code = match failure_reason {
+        HttpRequestFailureReason::Timeout
+        | HttpRequestFailureReason::NoClientAvailable
+        | HttpRequestFailureReason::ClientDisconnected
+        | HttpRequestFailureReason::UpstreamError(_)
+        | HttpRequestFailureReason::HttpsOutcallError(_)
+        | HttpRequestFailureReason::Interrupted
+        | HttpRequestFailureReason::CircuitOpen(_)
+        | HttpRequestFailureReason::RateLimited(_) => RejectionCode::SysTransient,
+        HttpRequestFailureReason::ResponseTooLarge(_) => RejectionCode::SysFatal,
+        _ => RejectionCode::CanisterReject,
+    };
+
+    (code, format!("{:?}", failure_reason))
+}
+
+fn response_size(response: &HttpResponse) -> u64 {
+    let headers_bytes: usize = response
+        .headers
+        .iter()
+        .map(|h| h.name.len() + h.value.len())
+        .sum();
+
+    (headers_bytes + response.body.len()) as u64
+}
+
+/// Like the management canister, only lets a canister transform its own responses.
+fn check_transform_owner(
+    transform: Option<&TransformContext>,
+    owner: Principal,
+) -> Result<(), String> {
+    match transform {
+        Some(transform) if transform.function.0.principal != owner => Err(format!(
+            "the transform function must be a method of {}",
+            owner
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct ManagementHttpRequestContext {
+    max_response_bytes: u64,
+    transform: Option<TransformContext>,
+    /// The only canister whose transform function can be called.
+    requester: Principal,
+    callback: HttpCallbackContext,
+}
+
+/// Must be called on every (re)install, see [register_http_callback].
+pub fn register_http_callbacks() {
+    register_http_callback(MANAGEMENT_RESPONSE_CALLBACK_NAME, |args| {
+        Box::pin(management_response_cb(args))
+    });
+}
+
+/// Sends the request to the clients, letting it fall back to an HTTPS outcall
+/// paid from `cycles` if no client is available.
+fn start_http_request(
+    arg: CanisterHttpRequestArgument,
+    cycles: u128,
+    requester: Option<Principal>,
+    callback: Option<HttpCallbackContext>,
+) -> HttpRequestId {
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+
+    let http_request = HttpRequest {
+        url: arg.url,
+        method: from_api_method(&arg.method),
+        headers: arg.headers,
+        body: arg.body,
+    };
+
+    execute_raw_http_request(
+        http_request,
+        HttpRequestOptions {
+            callback,
+            timeout_ms: Some(DEFAULT_TIMEOUT_MS),
+            https_outcall: (cycles > 0).then_some(HttpsOutcallPolicy {
+                always: false,
+                max_response_bytes: Some(max_response_bytes),
+                cycles: Some(cycles),
+            }),
+            requester,
+            ..Default::default()
+        },
+    )
+}
+
+/// The counterpart of [ic_cdk::api::management_canister::http_request::http_request],
+/// with the same argument and result types, which executes the request
+/// through the connected clients instead of HTTPS outcalls.
+///
+/// The `max_response_bytes` limit and the transform are applied like the management canister does.
+/// The transform must be a method of this canister.
+/// `cycles` are taken from the canister's balance only if the request falls back
+/// to an HTTPS outcall, because no client is available. With 0 cycles, only the clients are used.
+///
+/// The clients respond in later messages, so it must be awaited inside [ic_cdk::spawn]
+/// or a timer, like [super::execute_http_request_async].
+pub async fn http_request(
+    arg: CanisterHttpRequestArgument,
+    cycles: u128,
+) -> CallResult<(HttpResponse,)> {
+    check_transform_owner(arg.transform.as_ref(), id())
+        .map_err(|err| (RejectionCode::CanisterReject, err))?;
+
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    let transform = arg.transform.clone();
+
+    let request_id = start_http_request(arg, cycles, None, None);
+
+    let result = match wait_for_http_request(request_id).await {
+        Ok(response) => {
+            apply_management_semantics(response, max_response_bytes, transform, id()).await
+        }
+        Err(err) => Err(err),
+    };
+
+    result.map(|response| (response,)).map_err(to_rejection)
+}
+
+async fn apply_management_semantics(
+    response: HttpResponse,
+    max_response_bytes: u64,
+    transform: Option<TransformContext>,
+    requester: Principal,
+) -> HttpRequestResult {
+    if response_size(&response) > max_response_bytes {
+        return Err(HttpRequestFailureReason::ResponseTooLarge(
+            max_response_bytes,
+        ));
+    }
+
+    let Some(transform) = transform else {
+        return Ok(response);
+    };
+    check_transform_owner(Some(&transform), requester)
+        .map_err(HttpRequestFailureReason::TransformRejected)?;
+
+    let arg = encode_one(TransformArgs {
+        response,
+        context: transform.context,
+    })
+    .map_err(|e| HttpRequestFailureReason::TransformRejected(e.to_string()))?;
+
+    // no cycles are attached, like the management canister does
+    let reply = call_raw128(
+        transform.function.0.principal,
+        &transform.function.0.method,
+        arg,
+        0,
+    )
+    .await
+    .map_err(|(code, msg)| {
+        HttpRequestFailureReason::TransformRejected(format!("{:?}: {}", code, msg))
+    })?;
+
+    if reply.len() > MAX_TRANSFORM_REPLY_BYTES {
+        return Err(HttpRequestFailureReason::TransformRejected(format!(
+            "reply of {} bytes exceeds the limit of {} bytes",
+            reply.len(),
+            MAX_TRANSFORM_REPLY_BYTES
+        )));
+    }
+
+    let response: HttpResponse = decode_one(&reply)
+        .map_err(|e| HttpRequestFailureReason::TransformRejected(e.to_string()))?;
+
+    if response_size(&response) > max_response_bytes {
+        return Err(HttpRequestFailureReason::ResponseTooLarge(
+            max_response_bytes,
+        ));
+    }
+
+    Ok(response)
+}
+
+async fn management_response_cb(args: HttpCallbackArgs) {
+    let context: ManagementHttpRequestContext = match args.decode_context() {
+        Ok(context) => context,
+        Err(err) => {
+            // the callback to pass the failure to is part of the context
+            log(&format!(
+                "http_over_ws: invalid management context for HTTP request {}: {}",
+                args.request_id, err
+            ));
+            return;
+        }
+    };
+
+    let result = match args.result {
+        Ok(response) => {
+            apply_management_semantics(
+                response,
+                context.max_response_bytes,
+                context.transform,
+                context.requester,
+            )
+            .await
+        }
+        Err(err) => Err(err),
+    };
+
+    call_http_callback(args.request_id, context.callback, result).await;
+}
+
+/// Lets other canisters switch from the management canister's `http_request`
+/// by changing the callee and receiving the response in a callback.
+///
+/// The outcome is sent to the `callback_method` of the caller,
+/// with the `(HttpRequestId, HttpRequestResult)` arguments, like `proxy_http_request` does.
+/// The transform must be a method of the caller.
+///
+/// If enough cycles are attached, only the cost of an HTTPS outcall is accepted,
+/// so that the request can fall back to it if no client is available.
+/// The cycles that are not spent are sent back along with the outcome.
+///
+/// Only the allowed callers and the controllers can call it.
+#[update]
+fn http_request_over_ws(
+    arg: CanisterHttpRequestArgument,
+    callback_method: String,
+) -> Result<HttpRequestId, ExecuteHttpRequestError> {
+    let url =
+        Url::parse(&arg.url).map_err(|e| ExecuteHttpRequestError::InvalidUrl(e.to_string()))?;
+    let requester = caller();
+
+    check_transform_owner(arg.transform.as_ref(), requester)
+        .map_err(ExecuteHttpRequestError::InvalidTransform)?;
+    authorize_caller(requester, &url)?;
+
+    let max_response_bytes = arg.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    let outcall_cost = https_outcall::cost(
+        &HttpRequest {
+            url: arg.url.clone(),
+            method: from_api_method(&arg.method),
+            headers: arg.headers.clone(),
+            body: arg.body.clone(),
+        },
+        &HttpsOutcallPolicy {
+            always: false,
+            max_response_bytes: Some(max_response_bytes),
+            cycles: None,
+        },
+    );
+    let cycles = match outcall_cost {
+        Some(cost) if msg_cycles_available128() >= cost => msg_cycles_accept128(cost),
+        _ => 0,
+    };
+
+    let callback = HttpCallbackContext::with_candid_context(
+        MANAGEMENT_RESPONSE_CALLBACK_NAME,
+        &ManagementHttpRequestContext {
+            max_response_bytes,
+            transform: arg.transform.clone(),
+            requester,
+            callback: notify_requester_callback(&callback_method),
+        },
+    );
+
+    Ok(start_http_request(
+        arg,
+        cycles,
+        Some(requester),
+        Some(callback),
+    ))
+}
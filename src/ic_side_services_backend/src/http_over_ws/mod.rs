@@ -0,0 +1,1699 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    time::Duration,
+};
+
+use candid::{decode_one, encode_one, CandidType, Deserialize, Principal};
+use ic_cdk::{
+    api::is_controller,
+    api::management_canister::http_request::{
+        HttpHeader as ApiHttpHeader, HttpResponse as ApiHttpResponse,
+    },
+    caller, print, query, trap, update,
+};
+use ic_cdk_timers::TimerId;
+use ic_websocket_cdk::*;
+use url::Url;
+
+use crate::{
+    logger::log,
+    utils::{caller_is_controller, get_current_timestamp_ms, get_current_timestamp_ns},
+    ws::{close_client_connection, send_ws_message},
+};
+
+use callback::run_http_callback;
+use chunks::{
+    discard_chunked_response, receive_response_chunk, send_http_request, start_chunked_response,
+    BodyChunk, ChunkedBody,
+};
+use clients::{
+    ClientHealth, ClientOutcome, ClientSchedulingParams, ConnectedClients, SchedulingConfig,
+    SchedulingStrategy,
+};
+use destinations::{
+    admit_http_request, is_host_failure, record_destination_outcome, DestinationAdmission,
+};
+use executors::{admit_executor, release_quarantined_executor};
+use future::wake_http_request_waiters;
+use handshake::{send_hello, CanisterHello, ExecutorCapabilities};
+use https_outcall::execute_https_outcall;
+use lifecycle::{
+    set_http_request_status, start_deadline_timer, HttpRequestStatus, HttpRequestTransition,
+    DEFAULT_DEADLINE_MS,
+};
+use quorum::{QuorumOutcome, QuorumState};
+use transform::apply_http_transform;
+
+pub use callback::{register_http_callback, HttpCallbackArgs, HttpCallbackContext};
+pub use content::{decode_http_response, decode_json_response};
+pub use executors::start_executors_expiry_check;
+pub use future::wait_for_http_request;
+pub use https_outcall::HttpsOutcallPolicy;
+pub use quorum::HttpRequestQuorum;
+pub use redaction::SensitiveData;
+pub use retention::start_garbage_collection;
+pub use stable_state::{restore_stable_state, save_stable_state, HttpOverWsStableState};
+pub use transform::{register_http_transform, HttpTransformArgs, HttpTransformContext};
+
+mod access;
+mod callback;
+mod cancel;
+mod chunks;
+mod clients;
+mod content;
+mod destinations;
+mod executors;
+mod future;
+mod handshake;
+mod history;
+mod https_outcall;
+mod lifecycle;
+pub mod management;
+mod proxy;
+mod quorum;
+mod redaction;
+mod retention;
+mod stable_state;
+mod transform;
+
+/// How many requests can wait for a client to become available.
+const MAX_PENDING_HTTP_REQUESTS: usize = 100;
+/// How long a request can wait for a client to become available, if not specified otherwise.
+const DEFAULT_PENDING_DEADLINE_MS: u64 = 60_000;
+
+pub type HttpRequestId = u32;
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq)]
+pub enum HttpMethod {
+    GET,
+    POST,
+    PUT,
+    HEAD,
+    DELETE,
+}
+
+pub type HttpHeader = ApiHttpHeader;
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequest {
+    url: String,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<Vec<u8>>,
+}
+
+pub type HttpResponse = ApiHttpResponse;
+pub type HttpRequestResult = Result<HttpResponse, HttpRequestFailureReason>;
+
+#[derive(CandidType, Debug, Deserialize)]
+pub enum HttpOverWsMessage {
+    HttpRequest(HttpRequestId, HttpRequest),
+    HttpResponse(HttpRequestId, HttpResponse),
+    Error(Option<HttpRequestId>, String),
+    /// A request without body, which follows in [HttpOverWsMessage::BodyChunk]s.
+    ChunkedHttpRequest(HttpRequestId, HttpRequest, ChunkedBody),
+    /// A response without body, which follows in [HttpOverWsMessage::BodyChunk]s.
+    ChunkedHttpResponse(HttpRequestId, HttpResponse, ChunkedBody),
+    /// Part of the body of a chunked request, if sent by the canister,
+    /// or of a chunked response, if sent by the client.
+    BodyChunk(HttpRequestId, BodyChunk),
+    /// Sent by the canister in response to [HttpOverWsMessage::Capabilities].
+    Hello(CanisterHello),
+    /// Sent by the client when it opens the connection.
+    /// Clients that don't send it speak the protocol version 1.
+    Capabilities(ExecutorCapabilities),
+    /// Sent by the canister when a request the client is executing is cancelled.
+    Cancel(HttpRequestId),
+    /// Sent by the client when it can't get a response from the host of the request,
+    /// e.g. because the connection failed.
+    UpstreamError(HttpRequestId, String),
+}
+
+impl HttpOverWsMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_one(self).unwrap()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, candid::Error> {
+        decode_one(bytes)
+    }
+
+    /// What can be logged about the message.
+    /// The payload is left out, since headers and bodies may be sensitive.
+    fn summary(&self) -> String {
+        let (kind, request_id) = match self {
+            HttpOverWsMessage::HttpRequest(request_id, _) => ("HttpRequest", Some(request_id)),
+            HttpOverWsMessage::HttpResponse(request_id, _) => ("HttpResponse", Some(request_id)),
+            HttpOverWsMessage::Error(request_id, _) => ("Error", request_id.as_ref()),
+            HttpOverWsMessage::ChunkedHttpRequest(request_id, _, _) => {
+                ("ChunkedHttpRequest", Some(request_id))
+            }
+            HttpOverWsMessage::ChunkedHttpResponse(request_id, _, _) => {
+                ("ChunkedHttpResponse", Some(request_id))
+            }
+            HttpOverWsMessage::BodyChunk(request_id, _) => ("BodyChunk", Some(request_id)),
+            HttpOverWsMessage::Hello(_) => ("Hello", None),
+            HttpOverWsMessage::Capabilities(_) => ("Capabilities", None),
+            HttpOverWsMessage::Cancel(request_id) => ("Cancel", Some(request_id)),
+            HttpOverWsMessage::UpstreamError(request_id, _) => ("UpstreamError", Some(request_id)),
+        };
+
+        match request_id {
+            Some(request_id) => format!("{} for HTTP request {}", kind, request_id),
+            None => String::from(kind),
+        }
+    }
+}
+
+/// Why a message sent by a client was rejected.
+#[derive(Debug)]
+enum ClientMessageError {
+    /// The message could not be decoded.
+    InvalidMessage(String),
+    /// Clients are not allowed to send this kind of message.
+    UnexpectedMessage(&'static str),
+    UnknownRequest(HttpRequestId),
+    /// The request exists, but is not assigned to the client,
+    /// e.g. because it's assigned to another client.
+    RequestNotAssigned(HttpRequestId),
+    /// The client was freed from the request before responding,
+    /// e.g. because it timed out or was cancelled, so its late messages are expected.
+    RequestAbandoned(HttpRequestId),
+}
+
+impl ClientMessageError {
+    fn request_id(&self) -> Option<HttpRequestId> {
+        match self {
+            ClientMessageError::UnknownRequest(request_id)
+            | ClientMessageError::RequestNotAssigned(request_id)
+            | ClientMessageError::RequestAbandoned(request_id) => Some(*request_id),
+            ClientMessageError::InvalidMessage(_) | ClientMessageError::UnexpectedMessage(_) => {
+                None
+            }
+        }
+    }
+}
+
+impl fmt::Display for ClientMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientMessageError::InvalidMessage(err) => write!(f, "Invalid message: {}", err),
+            ClientMessageError::UnexpectedMessage(kind) => {
+                write!(f, "Clients are not allowed to send {} messages", kind)
+            }
+            ClientMessageError::UnknownRequest(request_id) => {
+                write!(f, "Unknown HTTP request {}", request_id)
+            }
+            ClientMessageError::RequestNotAssigned(request_id) => {
+                write!(
+                    f,
+                    "HTTP request {} is not assigned to the client",
+                    request_id
+                )
+            }
+            ClientMessageError::RequestAbandoned(request_id) => {
+                write!(
+                    f,
+                    "HTTP request {} is not awaited from the client anymore",
+                    request_id
+                )
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum HttpRequestFailureReason {
+    Timeout,
+    ErrorFromClient(String),
+    /// The client couldn't get a response from the host of the request.
+    UpstreamError(String),
+    /// No client became available before the request's pending deadline.
+    NoClientAvailable,
+    /// The client disconnected before sending the response.
+    ClientDisconnected,
+    /// The request was executed through an HTTPS outcall, which failed.
+    HttpsOutcallError(String),
+    /// Not enough clients agreed on the response.
+    QuorumNotReached,
+    /// The request's transform function rejected the response.
+    TransformRejected(String),
+    /// The canister was upgraded while the request was in progress,
+    /// and the request could not be dispatched again.
+    Interrupted,
+    /// The request was cancelled before completing.
+    Cancelled,
+    /// The circuit breaker of the host is open, after too many consecutive failures.
+    CircuitOpen(String),
+    /// The host's rate limit didn't allow sending the request before its pending deadline.
+    RateLimited(String),
+    /// The response exceeds the `max_response_bytes` limit, given in bytes,
+    /// of a [management::http_request].
+    ResponseTooLarge(u64),
+    /// Used when retrieving the request from the state
+    /// and the request is not found.
+    NotFound,
+    Unknown,
+}
+
+/// Defines if and how a failed HTTP request is sent again to another client.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequestRetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// How long to wait before the first retry.
+    /// The delay is doubled at each subsequent retry.
+    pub initial_backoff_ms: u64,
+    /// Retry when the client doesn't respond within the request timeout.
+    pub retry_on_timeout: bool,
+    /// Retry when the client responds with an [HttpOverWsMessage::Error]
+    /// or an [HttpOverWsMessage::UpstreamError],
+    /// or with a response rejected by the transform function.
+    pub retry_on_client_error: bool,
+    /// Retry when the client disconnects while the request is in flight.
+    /// These retries are dispatched right away, without backoff.
+    pub retry_on_client_disconnect: bool,
+}
+
+impl HttpRequestRetryPolicy {
+    fn is_retryable(&self, failure_reason: &HttpRequestFailureReason) -> bool {
+        match failure_reason {
+            HttpRequestFailureReason::Timeout => self.retry_on_timeout,
+            HttpRequestFailureReason::ErrorFromClient(_)
+            | HttpRequestFailureReason::UpstreamError(_)
+            | HttpRequestFailureReason::TransformRejected(_) => self.retry_on_client_error,
+            HttpRequestFailureReason::ClientDisconnected => self.retry_on_client_disconnect,
+            _ => false,
+        }
+    }
+
+    /// Returns the delay before the next attempt,
+    /// given the number of attempts already made.
+    fn backoff_ms(&self, attempts: u32) -> u64 {
+        let exponent = attempts.saturating_sub(1).min(16);
+        self.initial_backoff_ms.saturating_mul(1 << exponent)
+    }
+}
+
+impl Default for HttpRequestRetryPolicy {
+    /// Does not retry.
+    fn default() -> Self {
+        HttpRequestRetryPolicy {
+            max_attempts: 1,
+            initial_backoff_ms: 0,
+            retry_on_timeout: false,
+            retry_on_client_error: false,
+            retry_on_client_disconnect: false,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct HttpRequestOptions {
+    /// Called once, with the final outcome of the request.
+    pub callback: Option<HttpCallbackContext>,
+    /// How long each attempt waits for the client to respond.
+    pub timeout_ms: Option<u64>,
+    /// Defaults to [HttpRequestRetryPolicy::default].
+    pub retry_policy: Option<HttpRequestRetryPolicy>,
+    /// How long the request can wait for a client to become available.
+    /// Defaults to [DEFAULT_PENDING_DEADLINE_MS].
+    pub pending_deadline_ms: Option<u64>,
+    /// How long the request can take overall, retries included,
+    /// before failing with [HttpRequestFailureReason::Timeout].
+    /// Defaults to [DEFAULT_DEADLINE_MS].
+    pub deadline_ms: Option<u64>,
+    /// If set, HTTPS outcalls are used when no client is available.
+    pub https_outcall: Option<HttpsOutcallPolicy>,
+    /// If set, the request is executed by multiple clients.
+    pub quorum: Option<HttpRequestQuorum>,
+    /// Applied to every response, before it's stored and passed to the callback.
+    pub transform: Option<HttpTransformContext>,
+    /// The principal that requested the request, if it's not the canister itself.
+    /// Only this principal and the controllers can read the request and its outcome.
+    pub requester: Option<Principal>,
+    /// Redacted from the request and its response for everyone but the controllers.
+    /// Some headers are always redacted, see [SensitiveData].
+    pub sensitive: Option<SensitiveData>,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, PartialEq)]
+enum HttpRequestExecutor {
+    Client(ClientPrincipal),
+    HttpsOutcall,
+}
+
+/// A single dispatch of an HTTP request to a client or to the management canister.
+#[derive(CandidType, Clone, Deserialize)]
+struct HttpRequestAttempt {
+    executor: HttpRequestExecutor,
+    started_at: u64,
+    ended_at: Option<u64>,
+    failure_reason: Option<HttpRequestFailureReason>,
+}
+
+impl HttpRequestAttempt {
+    fn new(executor: HttpRequestExecutor) -> Self {
+        HttpRequestAttempt {
+            executor,
+            started_at: get_current_timestamp_ns(),
+            ended_at: None,
+            failure_reason: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct HttpRequestState {
+    request: HttpRequest,
+    response: Option<HttpResponse>,
+    callback: Option<HttpCallbackContext>,
+    timeout_ms: Option<u64>,
+    retry_policy: HttpRequestRetryPolicy,
+    pending_deadline_ms: u64,
+    https_outcall_policy: Option<HttpsOutcallPolicy>,
+    quorum: Option<QuorumState>,
+    transform: Option<HttpTransformContext>,
+    requester: Option<Principal>,
+    sensitive: SensitiveData,
+    attempts: Vec<HttpRequestAttempt>,
+    created_at: u64,
+    /// Set when the final outcome of the request is known.
+    completed_at: Option<u64>,
+    /// The timer of the current attempt's timeout, of the next retry
+    /// or of the pending deadline.
+    timer_id: Option<TimerId>,
+    failure_reason: Option<HttpRequestFailureReason>,
+    status: HttpRequestStatus,
+    timeline: Vec<HttpRequestTransition>,
+    deadline_ms: u64,
+    deadline_timer_id: Option<TimerId>,
+}
+
+impl HttpRequestState {
+    fn new(request: HttpRequest, options: HttpRequestOptions) -> Self {
+        HttpRequestState {
+            request,
+            response: None,
+            callback: options.callback,
+            timeout_ms: options.timeout_ms,
+            retry_policy: options.retry_policy.unwrap_or_default(),
+            pending_deadline_ms: options
+                .pending_deadline_ms
+                .unwrap_or(DEFAULT_PENDING_DEADLINE_MS),
+            https_outcall_policy: options.https_outcall,
+            quorum: options.quorum.map(QuorumState::new),
+            transform: options.transform,
+            requester: options.requester,
+            sensitive: options.sensitive.unwrap_or_default(),
+            attempts: vec![],
+            created_at: get_current_timestamp_ns(),
+            completed_at: None,
+            timer_id: None,
+            failure_reason: None,
+            status: HttpRequestStatus::Queued,
+            timeline: vec![HttpRequestTransition {
+                status: HttpRequestStatus::Queued,
+                at: get_current_timestamp_ns(),
+                detail: None,
+            }],
+            deadline_ms: options.deadline_ms.unwrap_or(DEFAULT_DEADLINE_MS),
+            deadline_timer_id: None,
+        }
+    }
+
+    /// An estimate of the heap used by the request and its response.
+    fn size_bytes(&self) -> u64 {
+        let headers_bytes = |headers: &[HttpHeader]| -> usize {
+            headers.iter().map(|h| h.name.len() + h.value.len()).sum()
+        };
+
+        let request_bytes = self.request.url.len()
+            + headers_bytes(&self.request.headers)
+            + self
+                .request
+                .body
+                .as_ref()
+                .map(|b| b.len())
+                .unwrap_or_default();
+        let response_bytes = self
+            .response
+            .as_ref()
+            .map(|res| headers_bytes(&res.headers) + res.body.len())
+            .unwrap_or_default();
+
+        (request_bytes + response_bytes) as u64
+    }
+
+    /// The final outcome of the request, if it's completed.
+    fn result(&self) -> Option<HttpRequestResult> {
+        self.completed_at?;
+
+        Some(match &self.response {
+            Some(response) => Ok(response.clone()),
+            None => Err(self
+                .failure_reason
+                .clone()
+                .unwrap_or(HttpRequestFailureReason::Unknown)),
+        })
+    }
+
+    fn is_readable_by(&self, principal: &Principal) -> bool {
+        self.requester
+            .is_none_or(|requester| requester == *principal || is_controller(principal))
+    }
+
+    fn is_cancellable_by(&self, principal: &Principal) -> bool {
+        self.requester == Some(*principal) || is_controller(principal)
+    }
+
+    fn clear_timer(&mut self) {
+        if let Some(timer_id) = self.timer_id.take() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
+    }
+
+    fn end_attempt(
+        &mut self,
+        executor: HttpRequestExecutor,
+        failure_reason: Option<HttpRequestFailureReason>,
+    ) {
+        if let Some(attempt) = self
+            .attempts
+            .iter_mut()
+            .rev()
+            .find(|a| a.executor == executor && a.ended_at.is_none())
+        {
+            attempt.ended_at = Some(get_current_timestamp_ns());
+            attempt.failure_reason = failure_reason;
+        }
+    }
+
+    fn attempted_clients(&self) -> Vec<ClientPrincipal> {
+        self.attempts
+            .iter()
+            .filter_map(|a| match a.executor {
+                HttpRequestExecutor::Client(client_principal) => Some(client_principal),
+                HttpRequestExecutor::HttpsOutcall => None,
+            })
+            .collect()
+    }
+
+    /// Takes the cycles reserved for the HTTPS outcall that haven't been spent.
+    fn take_unspent_cycles(&mut self) -> u128 {
+        self.https_outcall_policy
+            .as_mut()
+            .and_then(|policy| policy.cycles.take())
+            .unwrap_or_default()
+    }
+
+    fn prefers_https_outcall(&self) -> bool {
+        self.https_outcall_policy
+            .as_ref()
+            .is_some_and(|policy| policy.always)
+    }
+}
+
+thread_local! {
+    /* stable */ static HTTP_REQUESTS: RefCell<BTreeMap<HttpRequestId, HttpRequestState>> = RefCell::new(BTreeMap::new());
+    /* flexible */ static CONNECTED_CLIENTS: RefCell<ConnectedClients> = RefCell::new(ConnectedClients::new());
+    /* stable */ static NEXT_HTTP_REQUEST_ID: Cell<HttpRequestId> = const { Cell::new(1) };
+    /// Requests waiting for a client to become available, in arrival order.
+    /* flexible */ static PENDING_HTTP_REQUESTS: RefCell<VecDeque<HttpRequestId>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// Must be called on every (re)install, see [register_http_callback].
+pub fn register_http_callbacks() {
+    proxy::register_http_callbacks();
+    management::register_http_callbacks();
+}
+
+pub fn on_open(args: OnOpenCallbackArgs) {
+    if !admit_executor(args.client_principal) {
+        return;
+    }
+
+    CONNECTED_CLIENTS.with(|clients| {
+        clients.borrow_mut().add_client(args.client_principal);
+    });
+
+    dispatch_pending_http_requests();
+}
+
+pub fn on_message(args: OnMessageCallbackArgs) {
+    let client_principal = args.client_principal;
+
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .mark_client_seen(client_principal, get_current_timestamp_ms())
+    });
+
+    let incoming_msg = match HttpOverWsMessage::from_bytes(&args.message) {
+        Ok(incoming_msg) => incoming_msg,
+        Err(err) => {
+            reject_client_message(
+                client_principal,
+                ClientMessageError::InvalidMessage(err.to_string()),
+            );
+            return;
+        }
+    };
+
+    log(&format!(
+        "http_over_ws: incoming message: {} from {}",
+        incoming_msg.summary(),
+        client_principal
+    ));
+
+    match handle_client_message(client_principal, incoming_msg) {
+        Ok(()) => {}
+        // the client didn't know yet that it was freed from the request, it's not its fault
+        Err(err @ ClientMessageError::RequestAbandoned(_)) => log(&format!(
+            "http_over_ws: ignored late message from client {}: {}",
+            client_principal, err
+        )),
+        Err(err) => reject_client_message(client_principal, err),
+    }
+}
+
+fn handle_client_message(
+    client_principal: ClientPrincipal,
+    incoming_msg: HttpOverWsMessage,
+) -> Result<(), ClientMessageError> {
+    match incoming_msg {
+        HttpOverWsMessage::HttpRequest(_, _) | HttpOverWsMessage::ChunkedHttpRequest(_, _, _) => {
+            return Err(ClientMessageError::UnexpectedMessage("HTTP request"));
+        }
+        HttpOverWsMessage::Hello(_) => {
+            return Err(ClientMessageError::UnexpectedMessage("Hello"));
+        }
+        HttpOverWsMessage::Cancel(_) => {
+            return Err(ClientMessageError::UnexpectedMessage("Cancel"));
+        }
+        HttpOverWsMessage::HttpResponse(request_id, response) => {
+            check_request_assignment(client_principal, request_id)?;
+
+            handle_http_response(client_principal, request_id, response);
+        }
+        HttpOverWsMessage::ChunkedHttpResponse(request_id, response, body) => {
+            check_request_assignment(client_principal, request_id)?;
+
+            match start_chunked_response(client_principal, request_id, response, body) {
+                Ok(Some(response)) => handle_http_response(client_principal, request_id, response),
+                Ok(None) => {}
+                Err(err) => fail_http_request_attempt(
+                    client_principal,
+                    request_id,
+                    HttpRequestFailureReason::ErrorFromClient(err),
+                ),
+            }
+        }
+        HttpOverWsMessage::BodyChunk(request_id, chunk) => {
+            check_request_assignment(client_principal, request_id)?;
+
+            match receive_response_chunk(client_principal, request_id, chunk) {
+                Ok(Some(response)) => handle_http_response(client_principal, request_id, response),
+                Ok(None) => {}
+                Err(err) => fail_http_request_attempt(
+                    client_principal,
+                    request_id,
+                    HttpRequestFailureReason::ErrorFromClient(err),
+                ),
+            }
+        }
+        HttpOverWsMessage::Capabilities(capabilities) => {
+            // the client announced its version, so it can decode the reply.
+            // Quarantined clients take part in the handshake too,
+            // so that they can receive requests as soon as they're approved
+            send_hello(client_principal);
+
+            if !capabilities.is_protocol_supported() {
+                log(&format!(
+                    "http_over_ws: disconnecting client {} because of its unsupported protocol version {}",
+                    client_principal, capabilities.protocol_version
+                ));
+                close_client_connection(client_principal);
+                return Ok(());
+            }
+
+            CONNECTED_CLIENTS.with(|clients| {
+                clients
+                    .borrow_mut()
+                    .set_client_capabilities(client_principal, capabilities)
+            });
+
+            // requests may be waiting for a compatible client
+            dispatch_pending_http_requests();
+        }
+        HttpOverWsMessage::Error(request_id, err) => {
+            log(&format!("http_over_ws: incoming error: {}", err));
+
+            if let Some(request_id) = request_id {
+                check_request_assignment(client_principal, request_id)?;
+
+                fail_http_request_attempt(
+                    client_principal,
+                    request_id,
+                    HttpRequestFailureReason::ErrorFromClient(err),
+                );
+            }
+        }
+        HttpOverWsMessage::UpstreamError(request_id, err) => {
+            log(&format!("http_over_ws: incoming upstream error: {}", err));
+
+            check_request_assignment(client_principal, request_id)?;
+
+            fail_http_request_attempt(
+                client_principal,
+                request_id,
+                HttpRequestFailureReason::UpstreamError(err),
+            );
+        }
+    };
+
+    Ok(())
+}
+
+/// Logs the problem, reports it to the client and counts it in the client's stats.
+fn reject_client_message(client_principal: ClientPrincipal, err: ClientMessageError) {
+    log(&format!(
+        "http_over_ws: rejected message from client {}: {}",
+        client_principal, err
+    ));
+
+    CONNECTED_CLIENTS.with(|clients| clients.borrow_mut().record_protocol_error(client_principal));
+
+    send_ws_message(
+        client_principal,
+        HttpOverWsMessage::Error(err.request_id(), err.to_string()),
+    );
+}
+
+fn is_request_assigned_to_client(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+) -> bool {
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow()
+            .is_request_assigned_to_client(client_principal, request_id)
+    })
+}
+
+fn check_request_assignment(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+) -> Result<(), ClientMessageError> {
+    if is_request_assigned_to_client(client_principal, request_id) {
+        return Ok(());
+    }
+
+    let is_abandoned = CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow()
+            .is_request_abandoned_by_client(client_principal, request_id)
+    });
+    if is_abandoned {
+        return Err(ClientMessageError::RequestAbandoned(request_id));
+    }
+
+    let exists =
+        HTTP_REQUESTS.with(|http_requests| http_requests.borrow().contains_key(&request_id));
+
+    Err(if exists {
+        ClientMessageError::RequestNotAssigned(request_id)
+    } else {
+        ClientMessageError::UnknownRequest(request_id)
+    })
+}
+
+/// Handles the (reassembled) response of a client the request is assigned to.
+fn handle_http_response(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+    response: HttpResponse,
+) {
+    CONNECTED_CLIENTS.with(|clients| {
+        clients.borrow_mut().complete_request_for_client(
+            client_principal,
+            request_id,
+            Some(get_current_timestamp_ms()),
+        );
+    });
+    record_destination_outcome(request_id, !is_host_failure(&response));
+    set_http_request_status(
+        request_id,
+        HttpRequestStatus::Responded,
+        Some(client_principal.to_string()),
+    );
+
+    match transform_http_response(request_id, response) {
+        Ok(response) if is_quorum_request(request_id) => {
+            record_client_outcome(client_principal, ClientOutcome::Completed);
+            record_quorum_result(client_principal, request_id, Ok(response));
+        }
+        Ok(response) => {
+            record_client_outcome(client_principal, ClientOutcome::Completed);
+
+            HTTP_REQUESTS.with(|http_requests| {
+                if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+                    // response have been received, clear the timer
+                    r.clear_timer();
+                    r.end_attempt(HttpRequestExecutor::Client(client_principal), None);
+                }
+            });
+
+            complete_http_request(request_id, Ok(response));
+
+            log(&format!(
+                "http_over_ws: Completed HTTP request {}",
+                request_id
+            ));
+        }
+        Err(failure_reason) => {
+            fail_http_request_attempt(client_principal, request_id, failure_reason);
+        }
+    }
+
+    dispatch_pending_http_requests();
+}
+
+pub fn on_close(args: OnCloseCallbackArgs) {
+    release_quarantined_executor(&args.client_principal);
+
+    let in_flight_requests = CONNECTED_CLIENTS
+        .with(|clients| clients.borrow_mut().remove_client(&args.client_principal));
+
+    print(&format!(
+        "http_over_ws: Client {} disconnected",
+        args.client_principal
+    ));
+
+    for request_id in in_flight_requests {
+        log(&format!(
+            "http_over_ws: Client {} disconnected while executing HTTP request {}",
+            args.client_principal, request_id
+        ));
+
+        fail_http_request_attempt(
+            args.client_principal,
+            request_id,
+            HttpRequestFailureReason::ClientDisconnected,
+        );
+    }
+}
+
+/// Updates the client's stats and disconnects it if its score got too low.
+fn record_client_outcome(client_principal: ClientPrincipal, outcome: ClientOutcome) {
+    let must_disconnect = CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .record_client_outcome(client_principal, outcome)
+    });
+
+    if must_disconnect {
+        log(&format!(
+            "http_over_ws: disconnecting client {} because of its low score",
+            client_principal
+        ));
+        close_client_connection(client_principal);
+    }
+}
+
+fn http_request_timeout(client_principal: ClientPrincipal, request_id: HttpRequestId) {
+    log(&format!(
+        "http_over_ws: HTTP request with id {} timed out",
+        request_id
+    ));
+
+    fail_http_request_attempt(
+        client_principal,
+        request_id,
+        HttpRequestFailureReason::Timeout,
+    );
+}
+
+/// Frees the client from the request and, if the retry policy allows it,
+/// schedules a new attempt. Otherwise, completes the request with the failure.
+fn fail_http_request_attempt(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+    failure_reason: HttpRequestFailureReason,
+) {
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .complete_request_for_client(client_principal, request_id, None);
+    });
+    discard_chunked_response(client_principal, request_id);
+
+    match failure_reason {
+        // the client is already gone
+        HttpRequestFailureReason::ClientDisconnected => {}
+        // a slow host can't be told apart from a slow client
+        HttpRequestFailureReason::Timeout => {
+            record_client_outcome(client_principal, ClientOutcome::TimedOut);
+            record_destination_outcome(request_id, false);
+        }
+        // the client is not to blame for the host's failures
+        HttpRequestFailureReason::UpstreamError(_) => record_destination_outcome(request_id, false),
+        _ => record_client_outcome(client_principal, ClientOutcome::Failed),
+    }
+
+    if is_quorum_request(request_id) {
+        record_quorum_result(client_principal, request_id, Err(failure_reason));
+        dispatch_pending_http_requests();
+        return;
+    }
+
+    let backoff_ms = HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+        let r = http_requests.get_mut(&request_id)?;
+
+        r.clear_timer();
+        r.end_attempt(
+            HttpRequestExecutor::Client(client_principal),
+            Some(failure_reason.clone()),
+        );
+
+        let attempts = r.attempts.len() as u32;
+        if r.retry_policy.is_retryable(&failure_reason) && attempts < r.retry_policy.max_attempts {
+            r.failure_reason = Some(failure_reason.clone());
+            r.transition(
+                HttpRequestStatus::Queued,
+                Some(format!("retrying after {:?}", failure_reason)),
+            );
+
+            match failure_reason {
+                HttpRequestFailureReason::ClientDisconnected => Some(0),
+                _ => Some(r.retry_policy.backoff_ms(attempts)),
+            }
+        } else {
+            None
+        }
+    });
+
+    match backoff_ms {
+        Some(0) => retry_http_request(request_id),
+        Some(millis) => {
+            log(&format!(
+                "http_over_ws: retrying HTTP request {} in {}ms",
+                request_id, millis
+            ));
+
+            let timer_id = ic_cdk_timers::set_timer(Duration::from_millis(millis), move || {
+                retry_http_request(request_id);
+            });
+
+            HTTP_REQUESTS.with(|http_requests| {
+                if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+                    r.timer_id = Some(timer_id);
+                }
+            });
+        }
+        None => complete_http_request(request_id, Err(failure_reason)),
+    }
+
+    dispatch_pending_http_requests();
+}
+
+fn retry_http_request(request_id: HttpRequestId) {
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.timer_id = None;
+        }
+    });
+
+    if !start_http_request(request_id) {
+        let failure_reason = HTTP_REQUESTS
+            .with(|http_requests| {
+                http_requests
+                    .borrow()
+                    .get(&request_id)
+                    .and_then(|r| r.failure_reason.clone())
+            })
+            .unwrap_or(HttpRequestFailureReason::Unknown);
+
+        complete_http_request(request_id, Err(failure_reason));
+    }
+}
+
+/// Sends the request through the first available transport:
+/// a client, an HTTPS outcall (if allowed) or the pending queue.
+///
+/// The policy of the request's host is applied first: the request is delayed
+/// if the host's rate limit is reached, and completed right away if its circuit is open.
+///
+/// Returns `false` if none of the transports is available.
+fn start_http_request(request_id: HttpRequestId) -> bool {
+    match admit_http_request(request_id) {
+        DestinationAdmission::Allowed => {}
+        DestinationAdmission::Delayed(millis) => {
+            log(&format!(
+                "http_over_ws: rate limit reached, sending HTTP request {} in {}ms",
+                request_id, millis
+            ));
+            set_http_request_status(
+                request_id,
+                HttpRequestStatus::Queued,
+                Some(String::from("rate limited")),
+            );
+
+            let timer_id = ic_cdk_timers::set_timer(Duration::from_millis(millis), move || {
+                retry_http_request(request_id);
+            });
+
+            HTTP_REQUESTS.with(|http_requests| {
+                if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+                    r.timer_id = Some(timer_id);
+                }
+            });
+
+            return true;
+        }
+        DestinationAdmission::Rejected(failure_reason) => {
+            log(&format!(
+                "http_over_ws: HTTP request {} rejected: {:?}",
+                request_id, failure_reason
+            ));
+
+            complete_http_request(request_id, Err(failure_reason));
+            return true;
+        }
+    }
+
+    let prefers_https_outcall = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .is_some_and(|r| r.prefers_https_outcall())
+    });
+
+    if prefers_https_outcall && dispatch_https_outcall(request_id) {
+        return true;
+    }
+
+    dispatch_http_request(request_id)
+        || dispatch_https_outcall(request_id)
+        || enqueue_http_request(request_id)
+}
+
+/// Executes the request through the management canister's HTTPS outcalls.
+///
+/// Returns `false` if the request doesn't allow it, its method is not supported
+/// or the cycles paying for it, see [HttpsOutcallPolicy::cycles], don't cover its cost,
+/// so that it can wait for a client instead.
+fn dispatch_https_outcall(request_id: HttpRequestId) -> bool {
+    let Some((http_request, policy)) = HTTP_REQUESTS.with(|http_requests| {
+        http_requests.borrow().get(&request_id).and_then(|r| {
+            r.https_outcall_policy
+                .clone()
+                .filter(|policy| https_outcall::can_execute(&r.request, policy))
+                .map(|policy| (r.request.clone(), policy))
+        })
+    }) else {
+        return false;
+    };
+
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.attempts
+                .push(HttpRequestAttempt::new(HttpRequestExecutor::HttpsOutcall));
+            r.transition(
+                HttpRequestStatus::Assigned,
+                Some(String::from("HTTPS outcall")),
+            );
+        }
+    });
+
+    log(&format!(
+        "http_over_ws: executing HTTP request {} through an HTTPS outcall",
+        request_id
+    ));
+
+    ic_cdk::spawn(async move {
+        let (result, spent_cycles) = execute_https_outcall(http_request, policy).await;
+        let result = result.map_err(HttpRequestFailureReason::HttpsOutcallError);
+        record_destination_outcome(
+            request_id,
+            result
+                .as_ref()
+                .is_ok_and(|response| !is_host_failure(response)),
+        );
+        if result.is_ok() {
+            set_http_request_status(
+                request_id,
+                HttpRequestStatus::Responded,
+                Some(String::from("HTTPS outcall")),
+            );
+        }
+        let result = result.and_then(|response| transform_http_response(request_id, response));
+
+        HTTP_REQUESTS.with(|http_requests| {
+            if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+                if let Some(cycles) = r
+                    .https_outcall_policy
+                    .as_mut()
+                    .and_then(|policy| policy.cycles.as_mut())
+                {
+                    *cycles = cycles.saturating_sub(spent_cycles);
+                }
+                r.end_attempt(
+                    HttpRequestExecutor::HttpsOutcall,
+                    result.as_ref().err().cloned(),
+                );
+            }
+        });
+
+        complete_http_request(request_id, result);
+    });
+
+    true
+}
+
+/// Sends the request to a client, preferring the ones that haven't been tried yet.
+///
+/// Returns `false` if there are no clients available.
+fn dispatch_http_request(request_id: HttpRequestId) -> bool {
+    if is_quorum_request(request_id) {
+        return dispatch_quorum_http_request(request_id);
+    }
+
+    let Some((http_request, timeout_ms, attempted_clients)) = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .map(|r| (r.request.clone(), r.timeout_ms, r.attempted_clients()))
+    }) else {
+        return false;
+    };
+
+    let Some(assigned_client_principal) = CONNECTED_CLIENTS.with(|clients| {
+        clients.borrow_mut().assign_request(
+            request_id,
+            &http_request,
+            &attempted_clients,
+            get_current_timestamp_ms(),
+        )
+    }) else {
+        return false;
+    };
+
+    let timer_id = timeout_ms.map(|millis| {
+        ic_cdk_timers::set_timer(Duration::from_millis(millis), move || {
+            http_request_timeout(assigned_client_principal, request_id);
+        })
+    });
+
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.clear_timer();
+            r.timer_id = timer_id;
+            r.attempts
+                .push(HttpRequestAttempt::new(HttpRequestExecutor::Client(
+                    assigned_client_principal,
+                )));
+            r.transition(
+                HttpRequestStatus::Assigned,
+                Some(assigned_client_principal.to_string()),
+            );
+        }
+    });
+
+    send_http_request(assigned_client_principal, request_id, http_request);
+
+    true
+}
+
+/// Applies the request's transform function, if any.
+fn transform_http_response(request_id: HttpRequestId, response: HttpResponse) -> HttpRequestResult {
+    let Some(transform_context) = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .and_then(|r| r.transform.clone())
+    }) else {
+        return Ok(response);
+    };
+
+    apply_http_transform(&transform_context, response).map_err(|err| {
+        log(&format!(
+            "http_over_ws: transform {} rejected the response of HTTP request {}: {}",
+            transform_context.function, request_id, err
+        ));
+        HttpRequestFailureReason::TransformRejected(err)
+    })
+}
+
+fn is_quorum_request(request_id: HttpRequestId) -> bool {
+    HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .is_some_and(|r| r.quorum.is_some())
+    })
+}
+
+/// Sends the request to [HttpRequestQuorum::clients] distinct clients.
+///
+/// Returns `false` if less than [HttpRequestQuorum::min_agreeing] clients are available.
+fn dispatch_quorum_http_request(request_id: HttpRequestId) -> bool {
+    let Some((http_request, timeout_ms, quorum)) = HTTP_REQUESTS.with(|http_requests| {
+        http_requests.borrow().get(&request_id).and_then(|r| {
+            r.quorum
+                .as_ref()
+                .map(|q| (r.request.clone(), r.timeout_ms, q.config.clone()))
+        })
+    }) else {
+        return false;
+    };
+
+    let assigned_clients = CONNECTED_CLIENTS.with(|clients| {
+        clients.borrow_mut().assign_request_to_distinct_clients(
+            request_id,
+            &http_request,
+            quorum.clients as usize,
+            quorum.min_agreeing as usize,
+            get_current_timestamp_ms(),
+        )
+    });
+
+    if assigned_clients.is_empty() {
+        return false;
+    }
+
+    let timer_id = timeout_ms.map(|millis| {
+        ic_cdk_timers::set_timer(Duration::from_millis(millis), move || {
+            quorum_http_request_timeout(request_id);
+        })
+    });
+
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.clear_timer();
+            r.timer_id = timer_id;
+            for client_principal in &assigned_clients {
+                r.attempts
+                    .push(HttpRequestAttempt::new(HttpRequestExecutor::Client(
+                        *client_principal,
+                    )));
+            }
+            if let Some(q) = r.quorum.as_mut() {
+                q.pending_clients = assigned_clients.clone();
+            }
+            r.transition(
+                HttpRequestStatus::Assigned,
+                Some(format!("{} clients in quorum mode", assigned_clients.len())),
+            );
+        }
+    });
+
+    for client_principal in assigned_clients {
+        send_http_request(client_principal, request_id, http_request.clone());
+    }
+
+    true
+}
+
+fn quorum_http_request_timeout(request_id: HttpRequestId) {
+    let pending_clients = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow_mut()
+            .get_mut(&request_id)
+            .and_then(|r| {
+                r.timer_id = None;
+                r.quorum.as_ref().map(|q| q.pending_clients.clone())
+            })
+            .unwrap_or_default()
+    });
+
+    log(&format!(
+        "http_over_ws: {} clients didn't respond to HTTP request {} in time",
+        pending_clients.len(),
+        request_id
+    ));
+
+    for client_principal in pending_clients {
+        fail_http_request_attempt(
+            client_principal,
+            request_id,
+            HttpRequestFailureReason::Timeout,
+        );
+    }
+}
+
+/// Records the result of one of the clients executing the request in quorum mode,
+/// and completes the request once the quorum is reached or can't be reached anymore.
+fn record_quorum_result(
+    client_principal: ClientPrincipal,
+    request_id: HttpRequestId,
+    result: HttpRequestResult,
+) {
+    let Some((outcome, pending_clients)) = HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+        let r = http_requests.get_mut(&request_id)?;
+
+        r.end_attempt(
+            HttpRequestExecutor::Client(client_principal),
+            result.as_ref().err().cloned(),
+        );
+
+        let quorum = r.quorum.as_mut()?;
+        quorum.record(client_principal, result);
+        Some((quorum.evaluate(), quorum.pending_clients.clone()))
+    }) else {
+        return;
+    };
+
+    let result = match outcome {
+        QuorumOutcome::Pending => return,
+        QuorumOutcome::Reached {
+            response,
+            disagreeing_clients,
+        } => {
+            for client_principal in disagreeing_clients {
+                log(&format!(
+                    "http_over_ws: Client {} disagreed with the quorum on HTTP request {}",
+                    client_principal, request_id
+                ));
+
+                record_client_outcome(client_principal, ClientOutcome::QuorumDisagreement);
+            }
+
+            Ok(response)
+        }
+        QuorumOutcome::Unreachable => Err(HttpRequestFailureReason::QuorumNotReached),
+    };
+
+    // the outcome is known, the remaining clients don't need to respond anymore
+    CONNECTED_CLIENTS.with(|clients| {
+        let mut clients = clients.borrow_mut();
+        for client_principal in &pending_clients {
+            clients.complete_request_for_client(*client_principal, request_id, None);
+            discard_chunked_response(*client_principal, request_id);
+        }
+    });
+
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.clear_timer();
+            if let Some(q) = r.quorum.as_mut() {
+                q.pending_clients.clear();
+            }
+        }
+    });
+
+    log(&format!(
+        "http_over_ws: Completed HTTP request {} in quorum mode",
+        request_id
+    ));
+
+    complete_http_request(request_id, result);
+}
+
+/// Puts the request in the pending queue, until a client becomes available
+/// or its pending deadline expires.
+///
+/// Returns `false` if the queue is full.
+fn enqueue_http_request(request_id: HttpRequestId) -> bool {
+    let Some(pending_deadline_ms) = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .map(|r| r.pending_deadline_ms)
+    }) else {
+        return false;
+    };
+
+    let enqueued = PENDING_HTTP_REQUESTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        if pending.len() >= MAX_PENDING_HTTP_REQUESTS {
+            return false;
+        }
+        pending.push_back(request_id);
+        true
+    });
+
+    if enqueued {
+        set_http_request_status(
+            request_id,
+            HttpRequestStatus::Queued,
+            Some(String::from("waiting for a client")),
+        );
+
+        let timer_id =
+            ic_cdk_timers::set_timer(Duration::from_millis(pending_deadline_ms), move || {
+                pending_http_request_expired(request_id);
+            });
+
+        HTTP_REQUESTS.with(|http_requests| {
+            if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+                r.timer_id = Some(timer_id);
+            }
+        });
+
+        log(&format!(
+            "http_over_ws: no clients available, HTTP request {} is pending",
+            request_id
+        ));
+    }
+
+    enqueued
+}
+
+fn pending_http_request_expired(request_id: HttpRequestId) {
+    PENDING_HTTP_REQUESTS.with(|pending| pending.borrow_mut().retain(|id| *id != request_id));
+
+    HTTP_REQUESTS.with(|http_requests| {
+        if let Some(r) = http_requests.borrow_mut().get_mut(&request_id) {
+            r.timer_id = None;
+        }
+    });
+
+    log(&format!(
+        "http_over_ws: no clients became available for HTTP request {}",
+        request_id
+    ));
+
+    complete_http_request(request_id, Err(HttpRequestFailureReason::NoClientAvailable));
+}
+
+/// Dispatches the pending requests, in order, to the available compatible clients.
+fn dispatch_pending_http_requests() {
+    let pending_request_ids: Vec<HttpRequestId> =
+        PENDING_HTTP_REQUESTS.with(|pending| pending.borrow().iter().cloned().collect());
+
+    // a request that no compatible client can take stays in the queue,
+    // without blocking the ones behind it
+    for request_id in pending_request_ids {
+        if dispatch_http_request(request_id) {
+            PENDING_HTTP_REQUESTS
+                .with(|pending| pending.borrow_mut().retain(|id| *id != request_id));
+        }
+    }
+}
+
+/// Stores the final outcome of the request, runs its callback, if any,
+/// and wakes the tasks waiting for it.
+///
+/// The callback is taken from the state, so that it can only run once.
+/// The first outcome is the final one: later ones, e.g. the response
+/// of an HTTPS outcall for a cancelled request, are ignored.
+fn complete_http_request(request_id: HttpRequestId, result: HttpRequestResult) {
+    let is_completed = HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .is_some_and(|r| r.completed_at.is_some())
+    });
+    if is_completed {
+        return;
+    }
+
+    let callback = HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+        let r = http_requests.get_mut(&request_id)?;
+
+        r.completed_at = Some(get_current_timestamp_ns());
+        r.clear_deadline_timer();
+
+        match &result {
+            Ok(response) => {
+                r.response = Some(response.clone());
+                r.failure_reason = None;
+            }
+            Err(failure_reason) => {
+                r.failure_reason = Some(failure_reason.clone());
+            }
+        }
+
+        let callback = r.callback.take();
+        match &callback {
+            Some(callback) => r.transition(
+                HttpRequestStatus::CallbackRunning,
+                Some(callback.function.clone()),
+            ),
+            None => r.finish(),
+        }
+        callback
+    });
+
+    if let Some(callback) = callback {
+        run_http_callback(request_id, callback, result);
+    }
+
+    wake_http_request_waiters(request_id);
+}
+
+/// Sends the request to one of the connected clients.
+///
+/// If no client is available, the request is executed through an HTTPS outcall,
+/// if allowed by [HttpRequestOptions::https_outcall], or waits in the pending queue.
+/// Traps if the pending queue is full or if the quorum is invalid.
+pub fn execute_http_request(
+    url: Url,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<String>,
+    options: HttpRequestOptions,
+) -> HttpRequestId {
+    let http_request = HttpRequest {
+        url: url.to_string(),
+        method,
+        headers,
+        body: body.map(|b| b.into_bytes()),
+    };
+
+    execute_raw_http_request(http_request, options)
+}
+
+fn execute_raw_http_request(
+    http_request: HttpRequest,
+    options: HttpRequestOptions,
+) -> HttpRequestId {
+    if let Some(Err(err)) = options.quorum.as_ref().map(HttpRequestQuorum::validate) {
+        trap(&err);
+    }
+
+    let request_id = NEXT_HTTP_REQUEST_ID.with(|next_id| {
+        let id = next_id.get();
+        next_id.set(id + 1);
+        id
+    });
+
+    HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow_mut()
+            .insert(request_id, HttpRequestState::new(http_request, options));
+    });
+
+    start_deadline_timer(request_id);
+
+    if !start_http_request(request_id) {
+        trap("No available HTTP clients and too many pending HTTP requests");
+    }
+
+    request_id
+}
+
+/// Like [execute_http_request], but resolves with the final outcome of the request,
+/// so that multi-step flows can be written as straight-line code inside [ic_cdk::spawn].
+pub async fn execute_http_request_async(
+    url: Url,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<String>,
+    options: HttpRequestOptions,
+) -> HttpRequestResult {
+    let request_id = execute_http_request(url, method, headers, body, options);
+
+    wait_for_http_request(request_id).await
+}
+
+#[derive(CandidType, Deserialize)]
+struct PrettyHttpRequest {
+    url: String,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<String>,
+}
+
+/// The sensitive headers and body fields are redacted, unless the caller is a controller.
+#[query]
+fn get_http_request(request_id: HttpRequestId) -> Option<PrettyHttpRequest> {
+    let is_controller = is_controller(&caller());
+
+    HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .filter(|r| r.is_readable_by(&caller()))
+            .map(|r| {
+                let (headers, body) = if is_controller {
+                    (r.request.headers.clone(), r.request.body.clone())
+                } else {
+                    (
+                        r.sensitive.redact_headers(&r.request.headers),
+                        r.request.body.as_ref().map(|b| r.sensitive.redact_body(b)),
+                    )
+                };
+
+                PrettyHttpRequest {
+                    url: r.request.url.clone(),
+                    method: r.request.method.clone(),
+                    headers,
+                    body: body.map(|b| String::from_utf8_lossy(&b).to_string()),
+                }
+            })
+    })
+}
+
+#[derive(CandidType, Deserialize)]
+struct PrettyHttpResponse {
+    status: candid::Nat,
+    headers: Vec<HttpHeader>,
+    body: String,
+}
+
+type GetHttpResponseResult = Result<PrettyHttpResponse, HttpRequestFailureReason>;
+
+/// The response as the caller can read it: the sensitive headers and body fields
+/// are redacted, unless the caller is a controller.
+fn readable_http_response(request_id: HttpRequestId) -> HttpRequestResult {
+    let is_controller = is_controller(&caller());
+
+    HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .filter(|r| r.is_readable_by(&caller()))
+            .ok_or(HttpRequestFailureReason::NotFound)
+            .map(|r| {
+                r.response
+                    .as_ref()
+                    .ok_or(
+                        r.failure_reason
+                            .clone()
+                            .unwrap_or(HttpRequestFailureReason::Unknown),
+                    )
+                    .map(|res| HttpResponse {
+                        status: res.status.clone(),
+                        headers: if is_controller {
+                            res.headers.clone()
+                        } else {
+                            r.sensitive.redact_headers(&res.headers)
+                        },
+                        body: if is_controller {
+                            res.body.clone()
+                        } else {
+                            r.sensitive.redact_body(&res.body)
+                        },
+                    })
+            })?
+    })
+}
+
+/// The body is decoded as UTF-8, see [get_raw_http_response] for binary bodies.
+#[query]
+fn get_http_response(request_id: HttpRequestId) -> GetHttpResponseResult {
+    readable_http_response(request_id).map(|res| PrettyHttpResponse {
+        status: res.status,
+        headers: res.headers,
+        body: String::from_utf8_lossy(&res.body).to_string(),
+    })
+}
+
+/// Like [get_http_response], but returns the body as it was received.
+#[query]
+fn get_raw_http_response(request_id: HttpRequestId) -> HttpRequestResult {
+    readable_http_response(request_id)
+}
+
+#[query]
+fn get_http_request_attempts(request_id: HttpRequestId) -> Option<Vec<HttpRequestAttempt>> {
+    HTTP_REQUESTS.with(|http_requests| {
+        http_requests
+            .borrow()
+            .get(&request_id)
+            .filter(|r| r.is_readable_by(&caller()))
+            .map(|r| r.attempts.clone())
+    })
+}
+
+#[query]
+fn get_pending_http_requests() -> Vec<HttpRequestId> {
+    PENDING_HTTP_REQUESTS.with(|pending| pending.borrow().iter().cloned().collect())
+}
+
+#[query]
+fn get_connected_clients() -> ConnectedClients {
+    CONNECTED_CLIENTS.with(|clients| clients.borrow().clone())
+}
+
+/// The stats of the clients that connected since the last upgrade.
+#[query]
+fn get_clients_health() -> Vec<(ClientPrincipal, ClientHealth)> {
+    CONNECTED_CLIENTS.with(|clients| clients.borrow().clients_health())
+}
+
+#[query]
+fn get_scheduling_config() -> SchedulingConfig {
+    CONNECTED_CLIENTS.with(|clients| clients.borrow().scheduling_config().clone())
+}
+
+#[update(guard = "caller_is_controller")]
+fn set_scheduling_strategy(strategy: SchedulingStrategy) {
+    CONNECTED_CLIENTS.with(|clients| clients.borrow_mut().set_scheduling_strategy(strategy));
+}
+
+/// Scores go from 0 to 100, see [ClientHealth::score].
+#[update(guard = "caller_is_controller")]
+fn set_client_score_thresholds(
+    deprioritize_below_score: Option<u32>,
+    disconnect_below_score: Option<u32>,
+) {
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .set_score_thresholds(deprioritize_below_score, disconnect_below_score)
+    });
+}
+
+#[update(guard = "caller_is_controller")]
+fn set_default_max_concurrent_requests(max_concurrent_requests: Option<u32>) {
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .set_default_max_concurrent_requests(max_concurrent_requests)
+    });
+}
+
+/// Sets the scheduling parameters of a client, or resets them to the defaults if `params` is [None].
+#[update(guard = "caller_is_controller")]
+fn set_client_scheduling_params(
+    client_principal: ClientPrincipal,
+    params: Option<ClientSchedulingParams>,
+) {
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .set_client_scheduling_params(client_principal, params)
+    });
+}
+
+#[update]
+fn disconnect_client(client_principal: ClientPrincipal) {
+    close_client_connection(client_principal);
+}
+
+#[update]
+pub fn disconnect_all_clients() {
+    let clients = CONNECTED_CLIENTS.with(|state| state.borrow().client_principals());
+
+    for client_principal in clients {
+        disconnect_client(client_principal);
+    }
+}
@@ -0,0 +1,105 @@
+use candid::{CandidType, Deserialize};
+use ic_cdk::{api::call::notify_with_payment128, caller, update};
+use url::Url;
+
+use crate::logger::log;
+
+use super::{
+    access::{authorize_caller, ExecuteHttpRequestError},
+    execute_http_request, register_http_callback, HttpCallbackArgs, HttpCallbackContext,
+    HttpHeader, HttpMethod, HttpRequestId, HttpRequestOptions, SensitiveData, HTTP_REQUESTS,
+};
+
+const NOTIFY_REQUESTER_CALLBACK_NAME: &str = "http_over_ws_notify_requester";
+
+#[derive(CandidType, Deserialize)]
+struct ProxyHttpRequestArgs {
+    url: String,
+    method: HttpMethod,
+    headers: Vec<HttpHeader>,
+    body: Option<String>,
+    timeout_ms: Option<u64>,
+    /// The method of the calling canister that receives the outcome of the request,
+    /// with the `(HttpRequestId, HttpRequestResult)` arguments.
+    callback_method: String,
+    /// Redacted from the request and its response for everyone but the controllers.
+    sensitive: Option<SensitiveData>,
+}
+
+/// Sends the outcome of the request to the given method of the requester.
+pub fn notify_requester_callback(callback_method: &str) -> HttpCallbackContext {
+    HttpCallbackContext::with_candid_context(NOTIFY_REQUESTER_CALLBACK_NAME, &callback_method)
+}
+
+/// Must be called on every (re)install, see [register_http_callback].
+pub fn register_http_callbacks() {
+    register_http_callback(NOTIFY_REQUESTER_CALLBACK_NAME, |args| {
+        Box::pin(notify_requester_cb(args))
+    });
+}
+
+/// Sends the outcome of the request to the canister that requested it,
+/// along with the cycles it attached and that haven't been spent.
+///
+/// It's a one-way call, so that an unresponsive requester can't keep
+/// this canister waiting.
+async fn notify_requester_cb(args: HttpCallbackArgs) {
+    let callback_method: String = match args.decode_context() {
+        Ok(callback_method) => callback_method,
+        Err(err) => {
+            log(&format!(
+                "http_over_ws: invalid callback context for HTTP request {}: {}",
+                args.request_id, err
+            ));
+            return;
+        }
+    };
+
+    // the callback is only sent back to the principal recorded in the state
+    let Some((requester, unspent_cycles)) = HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+        let r = http_requests.get_mut(&args.request_id)?;
+        Some((r.requester?, r.take_unspent_cycles()))
+    }) else {
+        return;
+    };
+
+    if let Err(code) = notify_with_payment128(
+        requester,
+        &callback_method,
+        (args.request_id, args.result),
+        unspent_cycles,
+    ) {
+        log(&format!(
+            "http_over_ws: failed to notify {} about HTTP request {}: {:?}",
+            requester, args.request_id, code
+        ));
+    }
+}
+
+/// Like the `execute_http_request` endpoint, but the outcome of the request
+/// is sent to [ProxyHttpRequestArgs::callback_method] of the caller.
+#[update]
+fn proxy_http_request(
+    args: ProxyHttpRequestArgs,
+) -> Result<HttpRequestId, ExecuteHttpRequestError> {
+    let url =
+        Url::parse(&args.url).map_err(|e| ExecuteHttpRequestError::InvalidUrl(e.to_string()))?;
+    let requester = caller();
+
+    authorize_caller(requester, &url)?;
+
+    Ok(execute_http_request(
+        url,
+        args.method,
+        args.headers,
+        args.body,
+        HttpRequestOptions {
+            callback: Some(notify_requester_callback(&args.callback_method)),
+            timeout_ms: args.timeout_ms,
+            requester: Some(requester),
+            sensitive: args.sensitive,
+            ..Default::default()
+        },
+    ))
+}
@@ -0,0 +1,295 @@
+use ic_websocket_cdk::ClientPrincipal;
+
+use super::{HttpRequestResult, HttpResponse};
+
+/// Applied to the response body before comparing it with the other clients' ones.
+pub type HttpBodyNormalizer = fn(&[u8]) -> Vec<u8>;
+
+/// Sends the same request to multiple distinct clients
+/// and accepts the response only if enough of them agree on it.
+///
+/// The retry policy is not applied to requests executed in quorum mode.
+#[derive(Clone)]
+pub struct HttpRequestQuorum {
+    /// How many distinct clients receive the request.
+    pub clients: u32,
+    /// How many normalized responses must be equal to accept them.
+    /// Must be between 1 and [HttpRequestQuorum::clients].
+    pub min_agreeing: u32,
+    /// Headers (case-insensitive) dropped before comparing the responses.
+    pub ignored_headers: Vec<String>,
+    pub body_normalizer: Option<HttpBodyNormalizer>,
+}
+
+impl HttpRequestQuorum {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_agreeing == 0 || self.min_agreeing > self.clients {
+            return Err(format!(
+                "Invalid quorum: min_agreeing must be between 1 and {}, got {}",
+                self.clients, self.min_agreeing
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn normalize(&self, response: HttpResponse) -> HttpResponse {
+        let mut headers: Vec<_> = response
+            .headers
+            .into_iter()
+            .filter(|h| {
+                !self
+                    .ignored_headers
+                    .iter()
+                    .any(|ignored| ignored.eq_ignore_ascii_case(&h.name))
+            })
+            .map(|mut h| {
+                h.name = h.name.to_lowercase();
+                h
+            })
+            .collect();
+        // clients may send the headers in a different order
+        headers.sort_by(|a, b| (&a.name, &a.value).cmp(&(&b.name, &b.value)));
+
+        let body = match self.body_normalizer {
+            Some(normalizer) => normalizer(&response.body),
+            None => response.body,
+        };
+
+        HttpResponse {
+            status: response.status,
+            headers,
+            body,
+        }
+    }
+}
+
+pub enum QuorumOutcome {
+    /// Some clients still have to respond.
+    Pending,
+    Reached {
+        response: HttpResponse,
+        /// The clients that responded with a different response.
+        disagreeing_clients: Vec<ClientPrincipal>,
+    },
+    /// Not enough clients can agree anymore.
+    Unreachable,
+}
+
+/// The responses collected for a request executed in quorum mode.
+#[derive(Clone)]
+pub struct QuorumState {
+    pub config: HttpRequestQuorum,
+    /// The clients that still have to respond.
+    pub pending_clients: Vec<ClientPrincipal>,
+    /// The normalized responses received so far.
+    responses: Vec<(ClientPrincipal, HttpResponse)>,
+}
+
+impl QuorumState {
+    pub fn new(config: HttpRequestQuorum) -> Self {
+        QuorumState {
+            config,
+            pending_clients: vec![],
+            responses: vec![],
+        }
+    }
+
+    /// Records the client's result. Failed clients just don't count towards the quorum.
+    pub fn record(&mut self, client_principal: ClientPrincipal, result: HttpRequestResult) {
+        self.pending_clients.retain(|c| *c != client_principal);
+
+        if let Ok(response) = result {
+            let response = self.config.normalize(response);
+            self.responses.push((client_principal, response));
+        }
+    }
+
+    pub fn evaluate(&self) -> QuorumOutcome {
+        let mut groups: Vec<(&HttpResponse, Vec<ClientPrincipal>)> = vec![];
+        for (client_principal, response) in &self.responses {
+            match groups.iter_mut().find(|(r, _)| *r == response) {
+                Some((_, clients)) => clients.push(*client_principal),
+                None => groups.push((response, vec![*client_principal])),
+            }
+        }
+
+        let largest_group = groups.iter().max_by_key(|(_, clients)| clients.len());
+        let largest_group_size = largest_group.map(|(_, c)| c.len()).unwrap_or_default();
+
+        match largest_group {
+            Some((response, agreeing_clients))
+                if agreeing_clients.len() >= self.config.min_agreeing as usize =>
+            {
+                QuorumOutcome::Reached {
+                    response: (*response).clone(),
+                    disagreeing_clients: self
+                        .responses
+                        .iter()
+                        .map(|(c, _)| *c)
+                        .filter(|c| !agreeing_clients.contains(c))
+                        .collect(),
+                }
+            }
+            _ if largest_group_size + self.pending_clients.len()
+                < self.config.min_agreeing as usize =>
+            {
+                QuorumOutcome::Unreachable
+            }
+            _ => QuorumOutcome::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{Nat, Principal};
+
+    use super::super::{HttpHeader, HttpRequestFailureReason};
+    use super::*;
+
+    fn client(id: u8) -> ClientPrincipal {
+        Principal::from_slice(&[id])
+    }
+
+    fn response(body: &str) -> HttpResponse {
+        HttpResponse {
+            status: Nat::from(200u16),
+            headers: vec![],
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn header(name: &str, value: &str) -> HttpHeader {
+        HttpHeader {
+            name: String::from(name),
+            value: String::from(value),
+        }
+    }
+
+    fn quorum(clients: u8, min_agreeing: u32) -> QuorumState {
+        let mut state = QuorumState::new(HttpRequestQuorum {
+            clients: clients as u32,
+            min_agreeing,
+            ignored_headers: vec![String::from("date")],
+            body_normalizer: None,
+        });
+        state.pending_clients = (0..clients).map(client).collect();
+        state
+    }
+
+    #[test]
+    fn reject_invalid_min_agreeing() {
+        assert!(quorum(3, 0).config.validate().is_err());
+        assert!(quorum(3, 4).config.validate().is_err());
+        assert!(quorum(3, 3).config.validate().is_ok());
+    }
+
+    #[test]
+    fn reach_the_quorum_before_all_clients_respond() {
+        let mut state = quorum(3, 2);
+
+        state.record(client(0), Ok(response("a")));
+        assert!(matches!(state.evaluate(), QuorumOutcome::Pending));
+
+        state.record(client(1), Ok(response("a")));
+        match state.evaluate() {
+            QuorumOutcome::Reached {
+                response: reached,
+                disagreeing_clients,
+            } => {
+                assert_eq!(reached, response("a"));
+                assert!(disagreeing_clients.is_empty());
+            }
+            _ => panic!("the quorum must be reached"),
+        }
+        assert_eq!(state.pending_clients, vec![client(2)]);
+    }
+
+    #[test]
+    fn report_the_disagreeing_clients() {
+        let mut state = quorum(3, 2);
+
+        state.record(client(0), Ok(response("a")));
+        state.record(client(1), Ok(response("b")));
+        assert!(matches!(state.evaluate(), QuorumOutcome::Pending));
+
+        state.record(client(2), Ok(response("a")));
+        match state.evaluate() {
+            QuorumOutcome::Reached {
+                disagreeing_clients,
+                ..
+            } => assert_eq!(disagreeing_clients, vec![client(1)]),
+            _ => panic!("the quorum must be reached"),
+        }
+    }
+
+    #[test]
+    fn give_up_as_soon_as_the_quorum_is_unreachable() {
+        let mut state = quorum(3, 3);
+
+        state.record(client(0), Ok(response("a")));
+        state.record(client(1), Ok(response("b")));
+
+        // the last client can't make 3 agreeing responses anymore
+        assert!(matches!(state.evaluate(), QuorumOutcome::Unreachable));
+    }
+
+    #[test]
+    fn failed_clients_do_not_count_towards_the_quorum() {
+        let mut state = quorum(3, 2);
+
+        state.record(client(0), Err(HttpRequestFailureReason::Timeout));
+        state.record(client(1), Ok(response("a")));
+        assert!(matches!(state.evaluate(), QuorumOutcome::Pending));
+
+        state.record(client(2), Ok(response("a")));
+        match state.evaluate() {
+            QuorumOutcome::Reached {
+                disagreeing_clients,
+                ..
+            } => assert!(disagreeing_clients.is_empty()),
+            _ => panic!("the quorum must be reached"),
+        }
+    }
+
+    #[test]
+    fn too_many_failed_clients_make_the_quorum_unreachable() {
+        let mut state = quorum(3, 2);
+
+        state.record(client(0), Ok(response("a")));
+        state.record(client(1), Err(HttpRequestFailureReason::Timeout));
+        assert!(matches!(state.evaluate(), QuorumOutcome::Pending));
+
+        state.record(
+            client(2),
+            Err(HttpRequestFailureReason::ErrorFromClient(String::from(
+                "error",
+            ))),
+        );
+        assert!(matches!(state.evaluate(), QuorumOutcome::Unreachable));
+    }
+
+    #[test]
+    fn compare_the_normalized_responses() {
+        let mut state = quorum(2, 2);
+
+        let mut first = response("a");
+        first.headers = vec![
+            header("Content-Type", "text/plain"),
+            header("X-Id", "1"),
+            header("Date", "Mon"),
+        ];
+        let mut second = response("a");
+        second.headers = vec![
+            header("x-id", "1"),
+            header("content-type", "text/plain"),
+            header("date", "Tue"),
+        ];
+
+        state.record(client(0), Ok(first));
+        state.record(client(1), Ok(second));
+
+        assert!(matches!(state.evaluate(), QuorumOutcome::Reached { .. }));
+    }
+}
@@ -0,0 +1,212 @@
+use candid::{CandidType, Deserialize};
+use serde_json::Value;
+
+use super::HttpHeader;
+
+/// Replaces the values of the sensitive headers and body fields.
+const REDACTED: &str = "[REDACTED]";
+
+/// Headers that are redacted even if not marked as sensitive.
+/// `zelidauth` carries the Flux session.
+const ALWAYS_SENSITIVE_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "zelidauth",
+];
+
+/// The parts of a request and of its response that only the controllers can read.
+#[derive(CandidType, Clone, Debug, Default, Deserialize)]
+pub struct SensitiveData {
+    /// The names of the request and response headers, compared case-insensitively.
+    pub headers: Vec<String>,
+    /// The keys of the JSON request and response bodies, at any depth.
+    /// Bodies that are not valid JSON are redacted entirely.
+    pub body_fields: Vec<String>,
+}
+
+impl SensitiveData {
+    pub fn from_headers(headers: &[&str]) -> Self {
+        SensitiveData {
+            headers: headers.iter().map(|name| name.to_string()).collect(),
+            body_fields: vec![],
+        }
+    }
+
+    pub fn from_body_fields(body_fields: &[&str]) -> Self {
+        SensitiveData {
+            headers: vec![],
+            body_fields: body_fields.iter().map(|key| key.to_string()).collect(),
+        }
+    }
+
+    fn is_sensitive_header(&self, name: &str) -> bool {
+        ALWAYS_SENSITIVE_HEADERS
+            .iter()
+            .any(|header| header.eq_ignore_ascii_case(name))
+            || self
+                .headers
+                .iter()
+                .any(|header| header.eq_ignore_ascii_case(name))
+    }
+
+    pub fn redact_headers(&self, headers: &[HttpHeader]) -> Vec<HttpHeader> {
+        headers
+            .iter()
+            .map(|header| HttpHeader {
+                name: header.name.clone(),
+                value: if self.is_sensitive_header(&header.name) {
+                    String::from(REDACTED)
+                } else {
+                    header.value.clone()
+                },
+            })
+            .collect()
+    }
+
+    pub fn redact_body(&self, body: &[u8]) -> Vec<u8> {
+        if self.body_fields.is_empty() {
+            return body.to_vec();
+        }
+
+        let Ok(mut value) = serde_json::from_slice::<Value>(body) else {
+            return REDACTED.as_bytes().to_vec();
+        };
+        self.redact_json(&mut value);
+
+        serde_json::to_vec(&value).unwrap_or_else(|_| REDACTED.as_bytes().to_vec())
+    }
+
+    fn redact_json(&self, value: &mut Value) {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields.iter_mut() {
+                    if self.body_fields.contains(key) {
+                        *field = Value::String(String::from(REDACTED));
+                    } else {
+                        self.redact_json(field);
+                    }
+                }
+            }
+            Value::Array(items) => items.iter_mut().for_each(|item| self.redact_json(item)),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn header(name: &str, value: &str) -> HttpHeader {
+        HttpHeader {
+            name: String::from(name),
+            value: String::from(value),
+        }
+    }
+
+    fn redact_json_body(sensitive: &SensitiveData, body: Value) -> Value {
+        let redacted = sensitive.redact_body(&serde_json::to_vec(&body).unwrap());
+
+        serde_json::from_slice(&redacted).unwrap()
+    }
+
+    #[test]
+    fn redact_marked_headers_case_insensitively() {
+        let sensitive = SensitiveData::from_headers(&["X-Api-Key"]);
+
+        let redacted = sensitive.redact_headers(&[
+            header("x-api-key", "secret"),
+            header("Accept", "application/json"),
+        ]);
+
+        assert_eq!(redacted[0].name, "x-api-key");
+        assert_eq!(redacted[0].value, REDACTED);
+        assert_eq!(redacted[1].value, "application/json");
+    }
+
+    #[test]
+    fn always_redact_credentials_headers() {
+        let redacted = SensitiveData::default().redact_headers(&[
+            header("Authorization", "Bearer token"),
+            header("Cookie", "session=1"),
+            header("ZelIDAuth", "zelid"),
+            header("Content-Type", "application/json"),
+        ]);
+
+        let values: Vec<_> = redacted.iter().map(|h| h.value.as_str()).collect();
+        assert_eq!(
+            values,
+            vec![REDACTED, REDACTED, REDACTED, "application/json"]
+        );
+    }
+
+    #[test]
+    fn redact_body_fields_at_any_depth() {
+        let sensitive = SensitiveData::from_body_fields(&["signature", "token"]);
+
+        let redacted = redact_json_body(
+            &sensitive,
+            json!({
+                "signature": "abc",
+                "user": "alice",
+                "data": {
+                    "token": { "value": "xyz" },
+                    "items": [{ "signature": "def", "id": 1 }, "token"],
+                },
+            }),
+        );
+
+        assert_eq!(
+            redacted,
+            json!({
+                "signature": REDACTED,
+                "user": "alice",
+                "data": {
+                    "token": REDACTED,
+                    "items": [{ "signature": REDACTED, "id": 1 }, "token"],
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn redact_body_fields_in_top_level_array() {
+        let sensitive = SensitiveData::from_body_fields(&["password"]);
+
+        let redacted = redact_json_body(
+            &sensitive,
+            json!([{ "password": "secret" }, { "name": "bob" }]),
+        );
+
+        assert_eq!(
+            redacted,
+            json!([{ "password": REDACTED }, { "name": "bob" }])
+        );
+    }
+
+    #[test]
+    fn redact_non_json_body_entirely() {
+        let sensitive = SensitiveData::from_body_fields(&["password"]);
+
+        assert_eq!(
+            sensitive.redact_body(b"password=secret"),
+            REDACTED.as_bytes()
+        );
+        assert_eq!(sensitive.redact_body(b""), REDACTED.as_bytes());
+    }
+
+    #[test]
+    fn keep_body_without_sensitive_fields() {
+        let body = b"not even json";
+
+        assert_eq!(SensitiveData::default().redact_body(body), body);
+        assert_eq!(
+            SensitiveData::from_headers(&["x-api-key"]).redact_body(body),
+            body
+        );
+    }
+}
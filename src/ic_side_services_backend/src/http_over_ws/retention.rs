@@ -0,0 +1,201 @@
+use std::{cell::RefCell, collections::VecDeque, time::Duration};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::{api::is_controller, caller, query, update};
+
+use crate::{
+    logger::log,
+    utils::{caller_is_controller, get_current_timestamp_ns},
+};
+
+use super::{
+    lifecycle::HttpRequestStatus, HttpMethod, HttpRequestFailureReason, HttpRequestId,
+    HttpRequestState, HTTP_REQUESTS,
+};
+
+const GARBAGE_COLLECTION_INTERVAL_MS: u64 = 60_000;
+
+/// Limits on the completed requests kept in the state.
+/// Requests that are still in progress, or whose callback is still running, are never evicted.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct RetentionConfig {
+    /// Completed requests older than this are evicted.
+    pub max_age_ms: Option<u64>,
+    /// Oldest completed requests are evicted when there are more requests than this.
+    pub max_requests: Option<u64>,
+    /// Oldest completed requests are evicted when all requests and responses
+    /// take more bytes than this.
+    pub max_total_bytes: Option<u64>,
+    /// If set, a summary of the evicted requests is kept,
+    /// up to this many summaries.
+    pub max_archived_summaries: Option<u64>,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            max_age_ms: Some(24 * 60 * 60 * 1_000),
+            max_requests: Some(10_000),
+            max_total_bytes: Some(100 * 1024 * 1024),
+            max_archived_summaries: None,
+        }
+    }
+}
+
+/// What's left of a request after it's evicted.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpRequestSummary {
+    pub id: HttpRequestId,
+    pub url: String,
+    pub method: HttpMethod,
+    pub status: Option<candid::Nat>,
+    pub failure_reason: Option<HttpRequestFailureReason>,
+    pub created_at: u64,
+    pub completed_at: Option<u64>,
+    pub size_bytes: u64,
+    /// Only this principal and the controllers can read the summary, if set.
+    pub requester: Option<Principal>,
+}
+
+impl HttpRequestSummary {
+    fn is_readable_by(&self, principal: &Principal) -> bool {
+        self.requester
+            .is_none_or(|requester| requester == *principal || is_controller(principal))
+    }
+}
+
+impl HttpRequestSummary {
+    fn new(id: HttpRequestId, r: &HttpRequestState) -> Self {
+        HttpRequestSummary {
+            id,
+            url: r.request.url.clone(),
+            method: r.request.method.clone(),
+            status: r.response.as_ref().map(|res| res.status.clone()),
+            failure_reason: r.failure_reason.clone(),
+            created_at: r.created_at,
+            completed_at: r.completed_at,
+            size_bytes: r.size_bytes(),
+            requester: r.requester,
+        }
+    }
+}
+
+thread_local! {
+    /* stable */ static RETENTION_CONFIG: RefCell<RetentionConfig> = RefCell::new(RetentionConfig::default());
+    /* stable */ static ARCHIVED_HTTP_REQUESTS: RefCell<VecDeque<HttpRequestSummary>> = const { RefCell::new(VecDeque::new()) };
+}
+
+pub fn retention_config() -> RetentionConfig {
+    RETENTION_CONFIG.with(|c| c.borrow().clone())
+}
+
+pub fn set_retention_config(config: RetentionConfig) {
+    RETENTION_CONFIG.with(|c| *c.borrow_mut() = config);
+}
+
+/// Moves the summaries out of the archive, leaving it empty.
+pub fn take_archived_http_requests() -> Vec<HttpRequestSummary> {
+    ARCHIVED_HTTP_REQUESTS.with(|archive| std::mem::take(&mut *archive.borrow_mut()).into())
+}
+
+pub fn set_archived_http_requests(summaries: Vec<HttpRequestSummary>) {
+    ARCHIVED_HTTP_REQUESTS.with(|archive| *archive.borrow_mut() = summaries.into());
+}
+
+pub fn start_garbage_collection() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_millis(GARBAGE_COLLECTION_INTERVAL_MS),
+        collect_garbage,
+    );
+}
+
+/// Evicts the completed requests that exceed the [RetentionConfig] limits, oldest first.
+///
+/// The requests whose callback hasn't finished are kept,
+/// since the callback updates them when it returns.
+fn collect_garbage() {
+    let config = retention_config();
+    let now = get_current_timestamp_ns();
+
+    let evicted = HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+
+        let mut requests_count = http_requests.len() as u64;
+        let mut total_bytes: u64 = http_requests.values().map(|r| r.size_bytes()).sum();
+
+        // ids are monotonic, so iterating by key goes from the oldest to the newest request
+        let to_evict: Vec<HttpRequestId> = http_requests
+            .iter()
+            .filter(|(_, r)| r.status != HttpRequestStatus::CallbackRunning)
+            .filter_map(|(id, r)| r.completed_at.map(|completed_at| (*id, r, completed_at)))
+            .filter(|(_, r, completed_at)| {
+                let is_expired = config.max_age_ms.is_some_and(|max_age_ms| {
+                    now.saturating_sub(*completed_at) > max_age_ms * 1_000_000
+                });
+                let exceeds_count = config.max_requests.is_some_and(|max| requests_count > max);
+                let exceeds_bytes = config.max_total_bytes.is_some_and(|max| total_bytes > max);
+
+                let evict = is_expired || exceeds_count || exceeds_bytes;
+                if evict {
+                    requests_count -= 1;
+                    total_bytes = total_bytes.saturating_sub(r.size_bytes());
+                }
+                evict
+            })
+            .map(|(id, _, _)| id)
+            .collect();
+
+        to_evict
+            .into_iter()
+            .filter_map(|id| {
+                http_requests
+                    .remove(&id)
+                    .map(|r| HttpRequestSummary::new(id, &r))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    if evicted.is_empty() {
+        return;
+    }
+
+    log(&format!(
+        "http_over_ws: evicted {} completed HTTP requests",
+        evicted.len()
+    ));
+
+    if let Some(max_archived_summaries) = config.max_archived_summaries {
+        ARCHIVED_HTTP_REQUESTS.with(|archive| {
+            let mut archive = archive.borrow_mut();
+            archive.extend(evicted);
+            while archive.len() as u64 > max_archived_summaries {
+                archive.pop_front();
+            }
+        });
+    }
+}
+
+#[query]
+fn get_retention_config() -> RetentionConfig {
+    retention_config()
+}
+
+#[update(name = "set_retention_config", guard = "caller_is_controller")]
+fn set_retention_config_endpoint(config: RetentionConfig) {
+    set_retention_config(config);
+}
+
+/// Only returns the summaries readable by the caller.
+#[query]
+fn get_archived_http_requests() -> Vec<HttpRequestSummary> {
+    let caller = caller();
+
+    ARCHIVED_HTTP_REQUESTS.with(|archive| {
+        archive
+            .borrow()
+            .iter()
+            .filter(|summary| summary.is_readable_by(&caller))
+            .cloned()
+            .collect()
+    })
+}
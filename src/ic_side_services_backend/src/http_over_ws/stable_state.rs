@@ -0,0 +1,341 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_websocket_cdk::ClientPrincipal;
+
+use crate::{logger::log, utils::get_current_timestamp_ns};
+
+use super::{
+    access::{allowed_callers, set_allowed_callers, AllowedCaller},
+    clients::SchedulingConfig,
+    complete_http_request,
+    destinations::{destinations_config, set_destinations_config, DestinationsConfig},
+    executors::{
+        authorized_executors, set_authorized_executors, set_unauthorized_executor_policy,
+        unauthorized_executor_policy, AuthorizedExecutor, UnauthorizedExecutorPolicy,
+    },
+    https_outcall::{https_outcalls_cycles_budget, set_https_outcalls_cycles_budget},
+    lifecycle::{start_deadline_timer, HttpRequestStatus, HttpRequestTransition},
+    redaction::SensitiveData,
+    retention::{
+        retention_config, set_archived_http_requests, set_retention_config,
+        take_archived_http_requests, HttpRequestSummary, RetentionConfig,
+    },
+    start_http_request, HttpCallbackContext, HttpRequest, HttpRequestAttempt,
+    HttpRequestFailureReason, HttpRequestId, HttpRequestRetryPolicy, HttpRequestState,
+    HttpResponse, HttpTransformContext, HttpsOutcallPolicy, CONNECTED_CLIENTS, HTTP_REQUESTS,
+    NEXT_HTTP_REQUEST_ID,
+};
+
+/// The state of the HTTP-over-WS module that survives upgrades.
+///
+/// Optional fields can be added to the latest version, since they are decoded as [None]
+/// from the state saved by the previous version. Any other change to the persisted data
+/// must add a new variant, so that the previous state can still be decoded and migrated.
+#[derive(CandidType, Deserialize)]
+pub enum HttpOverWsStableState {
+    V1(StableStateV1),
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StableStateV1 {
+    next_http_request_id: HttpRequestId,
+    http_requests: Vec<StableHttpRequestV1>,
+    scheduling_config: SchedulingConfig,
+    allowed_callers: BTreeMap<Principal, AllowedCaller>,
+    authorized_executors: BTreeMap<ClientPrincipal, AuthorizedExecutor>,
+    unauthorized_executor_policy: UnauthorizedExecutorPolicy,
+    destinations_config: DestinationsConfig,
+    https_outcalls_cycles_budget: u128,
+    retention_config: RetentionConfig,
+    archived_http_requests: Vec<HttpRequestSummary>,
+}
+
+/// [HttpRequestState] without the quorum state and the timer,
+/// which can't be persisted.
+#[derive(CandidType, Deserialize)]
+struct StableHttpRequestV1 {
+    id: HttpRequestId,
+    request: HttpRequest,
+    response: Option<HttpResponse>,
+    failure_reason: Option<HttpRequestFailureReason>,
+    callback: Option<HttpCallbackContext>,
+    timeout_ms: Option<u64>,
+    retry_policy: HttpRequestRetryPolicy,
+    pending_deadline_ms: u64,
+    https_outcall_policy: Option<HttpsOutcallPolicy>,
+    transform: Option<HttpTransformContext>,
+    attempts: Vec<HttpRequestAttempt>,
+    created_at: u64,
+    completed_at: Option<u64>,
+    is_quorum: bool,
+    requester: Option<Principal>,
+    status: HttpRequestStatus,
+    timeline: Vec<HttpRequestTransition>,
+    deadline_ms: u64,
+    sensitive: SensitiveData,
+}
+
+impl StableHttpRequestV1 {
+    fn from_state(id: HttpRequestId, r: HttpRequestState) -> Self {
+        StableHttpRequestV1 {
+            id,
+            is_quorum: r.quorum.is_some(),
+            request: r.request,
+            response: r.response,
+            failure_reason: r.failure_reason,
+            callback: r.callback,
+            timeout_ms: r.timeout_ms,
+            retry_policy: r.retry_policy,
+            pending_deadline_ms: r.pending_deadline_ms,
+            https_outcall_policy: r.https_outcall_policy,
+            transform: r.transform,
+            attempts: r.attempts,
+            created_at: r.created_at,
+            completed_at: r.completed_at,
+            requester: r.requester,
+            status: r.status,
+            timeline: r.timeline,
+            deadline_ms: r.deadline_ms,
+            sensitive: r.sensitive,
+        }
+    }
+
+    /// An in-flight request can be dispatched again after the upgrade only if
+    /// nothing it depends on has been lost and executing it again is safe,
+    /// that is if it never reached an executor or its retry policy allows
+    /// another attempt after losing the executor.
+    fn is_resumable(&self) -> bool {
+        !self.is_quorum
+            && (self.attempts.is_empty()
+                || (self.retry_policy.retry_on_client_disconnect
+                    && (self.attempts.len() as u32) < self.retry_policy.max_attempts))
+    }
+
+    /// Ends the open attempts, since their executors will never respond.
+    fn interrupt(&mut self) {
+        let now = get_current_timestamp_ns();
+        for attempt in self.attempts.iter_mut().filter(|a| a.ended_at.is_none()) {
+            attempt.ended_at = Some(now);
+            attempt.failure_reason = Some(HttpRequestFailureReason::Interrupted);
+        }
+    }
+
+    fn into_state(self) -> HttpRequestState {
+        HttpRequestState {
+            request: self.request,
+            response: self.response,
+            callback: self.callback,
+            timeout_ms: self.timeout_ms,
+            retry_policy: self.retry_policy,
+            pending_deadline_ms: self.pending_deadline_ms,
+            https_outcall_policy: self.https_outcall_policy,
+            quorum: None,
+            transform: self.transform,
+            requester: self.requester,
+            attempts: self.attempts,
+            created_at: self.created_at,
+            completed_at: self.completed_at,
+            timer_id: None,
+            failure_reason: self.failure_reason,
+            status: self.status,
+            timeline: self.timeline,
+            deadline_ms: self.deadline_ms,
+            deadline_timer_id: None,
+            sensitive: self.sensitive,
+        }
+    }
+}
+
+/// To be called in the `pre_upgrade` hook.
+///
+/// The requests and the archive are moved out rather than cloned,
+/// so that their bodies are not held twice in the heap.
+pub fn save_stable_state() -> HttpOverWsStableState {
+    HttpOverWsStableState::V1(StableStateV1 {
+        next_http_request_id: NEXT_HTTP_REQUEST_ID.with(|next_id| next_id.get()),
+        http_requests: HTTP_REQUESTS.with(|http_requests| {
+            std::mem::take(&mut *http_requests.borrow_mut())
+                .into_iter()
+                .map(|(id, r)| StableHttpRequestV1::from_state(id, r))
+                .collect()
+        }),
+        scheduling_config: CONNECTED_CLIENTS
+            .with(|clients| clients.borrow().scheduling_config().clone()),
+        allowed_callers: allowed_callers(),
+        authorized_executors: authorized_executors(),
+        unauthorized_executor_policy: unauthorized_executor_policy(),
+        destinations_config: destinations_config(),
+        https_outcalls_cycles_budget: https_outcalls_cycles_budget(),
+        retention_config: retention_config(),
+        archived_http_requests: take_archived_http_requests(),
+    })
+}
+
+/// To be called in the `post_upgrade` hook.
+///
+/// The requests that were in flight during the upgrade are dispatched again
+/// if possible, otherwise they are completed with [HttpRequestFailureReason::Interrupted].
+pub fn restore_stable_state(state: HttpOverWsStableState) {
+    let HttpOverWsStableState::V1(state) = state;
+
+    NEXT_HTTP_REQUEST_ID.with(|next_id| next_id.set(state.next_http_request_id));
+    CONNECTED_CLIENTS.with(|clients| {
+        clients
+            .borrow_mut()
+            .set_scheduling_config(state.scheduling_config)
+    });
+    set_allowed_callers(state.allowed_callers);
+    set_authorized_executors(state.authorized_executors);
+    set_unauthorized_executor_policy(state.unauthorized_executor_policy);
+    set_destinations_config(state.destinations_config);
+    set_https_outcalls_cycles_budget(state.https_outcalls_cycles_budget);
+    set_retention_config(state.retention_config);
+    set_archived_http_requests(state.archived_http_requests);
+
+    let mut in_flight_requests = vec![];
+
+    HTTP_REQUESTS.with(|http_requests| {
+        let mut http_requests = http_requests.borrow_mut();
+
+        for mut r in state.http_requests {
+            if r.completed_at.is_none() {
+                r.interrupt();
+                in_flight_requests.push((r.id, r.is_resumable()));
+            }
+
+            http_requests.insert(r.id, r.into_state());
+        }
+    });
+
+    for (request_id, _) in &in_flight_requests {
+        start_deadline_timer(*request_id);
+    }
+
+    if !in_flight_requests.is_empty() {
+        // HTTPS outcalls and the callbacks' calls can't be made from the post_upgrade hook
+        ic_cdk_timers::set_timer(Duration::ZERO, move || {
+            resume_http_requests(in_flight_requests)
+        });
+    }
+}
+
+fn resume_http_requests(in_flight_requests: Vec<(HttpRequestId, bool)>) {
+    log(&format!(
+        "http_over_ws: resuming {} HTTP requests interrupted by the upgrade",
+        in_flight_requests.len()
+    ));
+
+    for (request_id, is_resumable) in in_flight_requests {
+        // the request may have been completed in the meantime, e.g. by its deadline
+        let is_completed = HTTP_REQUESTS.with(|http_requests| {
+            http_requests
+                .borrow()
+                .get(&request_id)
+                .is_none_or(|r| r.completed_at.is_some())
+        });
+        if is_completed {
+            continue;
+        }
+
+        if !is_resumable || !start_http_request(request_id) {
+            complete_http_request(request_id, Err(HttpRequestFailureReason::Interrupted));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::{decode_one, encode_one, Nat};
+
+    use super::*;
+    use crate::http_over_ws::HttpMethod;
+
+    fn completed_http_request() -> HttpRequestState {
+        HttpRequestState {
+            request: HttpRequest {
+                url: String::from("https://example.com/"),
+                method: HttpMethod::POST,
+                headers: vec![],
+                body: Some(b"request".to_vec()),
+            },
+            response: Some(HttpResponse {
+                status: Nat::from(200u16),
+                headers: vec![],
+                body: b"response".to_vec(),
+            }),
+            callback: None,
+            timeout_ms: Some(1_000),
+            retry_policy: Default::default(),
+            pending_deadline_ms: 0,
+            https_outcall_policy: None,
+            quorum: None,
+            transform: None,
+            requester: Some(Principal::anonymous()),
+            sensitive: Default::default(),
+            attempts: vec![],
+            created_at: 1,
+            completed_at: Some(2),
+            timer_id: None,
+            failure_reason: None,
+            status: HttpRequestStatus::Completed,
+            timeline: vec![],
+            deadline_ms: 0,
+            deadline_timer_id: None,
+        }
+    }
+
+    #[test]
+    fn save_moves_the_requests_out() {
+        HTTP_REQUESTS.with(|http_requests| {
+            http_requests
+                .borrow_mut()
+                .insert(7, completed_http_request())
+        });
+
+        let HttpOverWsStableState::V1(state) = save_stable_state();
+
+        assert!(HTTP_REQUESTS.with(|http_requests| http_requests.borrow().is_empty()));
+        assert_eq!(state.http_requests.len(), 1);
+        assert_eq!(state.http_requests[0].id, 7);
+    }
+
+    #[test]
+    fn round_trip_the_state_through_candid() {
+        let state = HttpOverWsStableState::V1(StableStateV1 {
+            next_http_request_id: 8,
+            http_requests: vec![StableHttpRequestV1::from_state(7, completed_http_request())],
+            scheduling_config: Default::default(),
+            allowed_callers: BTreeMap::new(),
+            authorized_executors: BTreeMap::new(),
+            unauthorized_executor_policy: UnauthorizedExecutorPolicy::Reject,
+            destinations_config: Default::default(),
+            https_outcalls_cycles_budget: 1_000,
+            retention_config: Default::default(),
+            archived_http_requests: vec![],
+        });
+
+        let bytes = encode_one(state).unwrap();
+        let HttpOverWsStableState::V1(state) = decode_one(&bytes).unwrap();
+
+        assert_eq!(state.next_http_request_id, 8);
+        assert_eq!(
+            state.unauthorized_executor_policy,
+            UnauthorizedExecutorPolicy::Reject
+        );
+        assert_eq!(state.https_outcalls_cycles_budget, 1_000);
+        assert_eq!(state.http_requests.len(), 1);
+
+        let r = state.http_requests.into_iter().next().unwrap();
+        assert_eq!(r.id, 7);
+        assert!(!r.is_quorum);
+
+        let r = r.into_state();
+        assert_eq!(r.request.body, Some(b"request".to_vec()));
+        assert_eq!(r.response.unwrap().body, b"response".to_vec());
+        assert_eq!(r.requester, Some(Principal::anonymous()));
+        assert_eq!(r.timeout_ms, Some(1_000));
+        assert_eq!(r.completed_at, Some(2));
+        assert_eq!(r.status, HttpRequestStatus::Completed);
+    }
+}
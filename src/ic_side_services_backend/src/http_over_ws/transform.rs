@@ -0,0 +1,66 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use candid::{CandidType, Deserialize};
+
+use super::HttpResponse;
+
+/// Like the management canister's `TransformArgs`.
+pub struct HttpTransformArgs {
+    pub response: HttpResponse,
+    pub context: Vec<u8>,
+}
+
+/// Transforms the response before it's stored and passed to the callback.
+///
+/// Returning an error rejects the response, as if the client had failed.
+pub type HttpTransformFunction = fn(HttpTransformArgs) -> Result<HttpResponse, String>;
+
+/// References a transform function by the name it was registered with,
+/// so that it can be stored along with the request.
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct HttpTransformContext {
+    pub function: String,
+    pub context: Vec<u8>,
+}
+
+impl HttpTransformContext {
+    pub fn from_name(function: &str, context: Vec<u8>) -> Self {
+        HttpTransformContext {
+            function: function.to_string(),
+            context,
+        }
+    }
+}
+
+thread_local! {
+    /// Function pointers don't survive upgrades, so transforms must be registered again on every (re)install.
+    /* flexible */ static HTTP_TRANSFORMS: RefCell<HashMap<String, HttpTransformFunction>> = RefCell::new(HashMap::new());
+}
+
+pub fn register_http_transform(name: &str, function: HttpTransformFunction) {
+    HTTP_TRANSFORMS.with(|transforms| transforms.borrow_mut().insert(name.to_string(), function));
+}
+
+pub fn apply_http_transform(
+    transform_context: &HttpTransformContext,
+    response: HttpResponse,
+) -> Result<HttpResponse, String> {
+    let function = HTTP_TRANSFORMS
+        .with(|transforms| {
+            transforms
+                .borrow()
+                .get(&transform_context.function)
+                .cloned()
+        })
+        .ok_or_else(|| {
+            format!(
+                "transform function {} is not registered",
+                transform_context.function
+            )
+        })?;
+
+    function(HttpTransformArgs {
+        response,
+        context: transform_context.context.clone(),
+    })
+}
@@ -6,6 +6,7 @@ use ecdsa_api::{
     EcdsaPublicKey,
 };
 use flux_api::authentication::{get_zelidauth, set_zelidauth};
+use http_over_ws::HttpOverWsStableState;
 use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
 
 use flux::FluxNetwork;
@@ -15,7 +16,7 @@ use ws::init_ws;
 mod ecdsa_api;
 mod flux;
 mod flux_api;
-mod http_over_ws;
+pub mod http_over_ws;
 mod logger;
 mod utils;
 mod ws;
@@ -28,6 +29,11 @@ thread_local! {
 #[init]
 fn init(network: FluxNetwork) {
     init_ws();
+    http_over_ws::start_garbage_collection();
+    http_over_ws::start_executors_expiry_check();
+    flux_api::register_http_transforms();
+    http_over_ws::register_http_callbacks();
+    flux_api::register_http_callbacks();
 
     NETWORK.with(|n| n.set(network));
 
@@ -44,20 +50,30 @@ fn pre_upgrade() {
     let network = NETWORK.with(|n| n.get());
     let ecdsa_pub_key = get_canister_ecdsa_public_key();
     let zelidauth = get_zelidauth().map(|h| h.value);
+    let http_over_ws_state = http_over_ws::save_stable_state();
 
-    ic_cdk::storage::stable_save((network, ecdsa_pub_key, zelidauth))
+    ic_cdk::storage::stable_save((network, ecdsa_pub_key, zelidauth, Some(http_over_ws_state)))
         .expect("Saving network to stable store must succeed.");
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    let (network, ecdsa_pub_key, zelidauth) =
-        ic_cdk::storage::stable_restore::<(FluxNetwork, EcdsaPublicKey, Option<String>)>()
-            .expect("Failed to read network from stable memory.");
+    let (network, ecdsa_pub_key, zelidauth, http_over_ws_state) =
+        ic_cdk::storage::stable_restore::<(
+            FluxNetwork,
+            EcdsaPublicKey,
+            Option<String>,
+            // not present when upgrading from older versions
+            Option<HttpOverWsStableState>,
+        )>()
+        .expect("Failed to read network from stable memory.");
 
     init(network);
     set_canister_ecdsa_public_key(ecdsa_pub_key);
     set_zelidauth(zelidauth);
+    if let Some(http_over_ws_state) = http_over_ws_state {
+        http_over_ws::restore_stable_state(http_over_ws_state);
+    }
 }
 
 /// Sets the ECDSA public key by fetching it from the ECDSA API.
@@ -7,3 +7,12 @@ pub fn get_current_timestamp_ns() -> u64 {
 pub fn get_current_timestamp_ms() -> u64 {
     get_current_timestamp_ns() / 1_000_000
 }
+
+/// Guard that only allows the canister controllers to call a method.
+pub fn caller_is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(String::from("Caller is not a controller"))
+    }
+}